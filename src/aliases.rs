@@ -0,0 +1,147 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Fixed users of recurring senders.
+//!
+//! The alias file maps sender addresses or domains to users and is
+//! consulted before the sources of `user_from`:
+//!
+//! ```toml
+//! "billing@vendor.com" = "accounting"
+//! "hetzner.com" = "it"
+//! "*.telekom.de" = "it"
+//! "invoice*@*.example.org" = "purchasing"
+//! ```
+//!
+//! Keys with an `@` match the whole address, other keys the domain. `*`
+//! matches any text and `?` a single character, case is ignored. Exact
+//! addresses win over exact domains, which win over patterns. Of several
+//! matching patterns the longest one is used.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Users of sender addresses and domains
+#[derive(Debug, Clone, Default)]
+pub struct AliasTable {
+    /// Lowercase keys without wildcards
+    exact: BTreeMap<String, String>,
+    /// Lowercase patterns with the user, longest first
+    patterns: Vec<(String, String)>,
+}
+
+impl AliasTable {
+    /// Reads the toml alias file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Can't read alias file {}", path.display()))?;
+        let entries: BTreeMap<String, String> = toml::from_str(&content)
+            .with_context(|| format!("Invalid alias file {}", path.display()))?;
+        Ok(Self::new(entries))
+    }
+
+    pub fn new(entries: BTreeMap<String, String>) -> Self {
+        let mut table = Self::default();
+        for (key, user) in entries {
+            let key = key.trim().to_lowercase();
+            if key.contains(['*', '?']) {
+                table.patterns.push((key, user));
+            } else {
+                table.exact.insert(key, user);
+            }
+        }
+        table.patterns.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+        table
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.patterns.is_empty()
+    }
+
+    /// Returns the user of the first address of the `From` header
+    pub fn resolve(&self, from: &str) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+        let address = crate::users::first_address(from)?.to_lowercase();
+        let (_, domain) = address.rsplit_once('@')?;
+        if let Some(user) = self.exact.get(&address).or_else(|| self.exact.get(domain)) {
+            return Some(user.clone());
+        }
+        self.patterns
+            .iter()
+            .find(|(pattern, _)| {
+                let subject = if pattern.contains('@') {
+                    &address
+                } else {
+                    domain
+                };
+                glob_matches(pattern, subject)
+            })
+            .map(|(_, user)| user.clone())
+    }
+}
+
+/// Matches `*` and `?` wildcards against the whole text
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of the last star and the text it matched up to
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("aliases.toml");
+        std::fs::write(
+            &path,
+            r#"
+            "Billing@vendor.com" = "accounting"
+            "vendor.com" = "purchasing"
+            "*.telekom.de" = "it"
+            "invoice*@*.example.org" = "office"
+            "*.example.org" = "sales"
+            "#,
+        )
+        .unwrap();
+        let table = AliasTable::from_file(&path).unwrap();
+        let resolve = |from: &str| table.resolve(from);
+        assert_eq!(
+            resolve("Vendor <billing@VENDOR.com>").as_deref(),
+            Some("accounting")
+        );
+        assert_eq!(resolve("shop@vendor.com").as_deref(), Some("purchasing"));
+        assert_eq!(resolve("rechnung@mail.telekom.de").as_deref(), Some("it"));
+        assert_eq!(
+            resolve("invoice-2023@eu.example.org").as_deref(),
+            Some("office")
+        );
+        assert_eq!(resolve("info@eu.example.org").as_deref(), Some("sales"));
+        assert_eq!(resolve("rechnung@telekom.de"), None);
+        assert!(glob_matches("a*b?c", "axxbyc"));
+        assert!(!glob_matches("a*b?c", "axxbc"));
+    }
+}
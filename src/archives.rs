@@ -0,0 +1,205 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Unpacking of archive attachments.
+//!
+//! With `--unpack-archives` zip and tar.gz attachments are opened in memory
+//! and the contained files of the accepted mimetypes are stored like
+//! attachments. The type of a contained file is guessed from its extension,
+//! or detected from its content for unknown extensions. Encrypted zip
+//! archives are opened with the password of the sender, see
+//! [`crate::passwords`].
+
+use crate::passwords::PasswordError;
+use crate::{sniff, MimeArguments};
+use anyhow::{bail, Context, Result};
+use std::io::{Cursor, Read};
+
+/// Archives with more unpacked bytes are rejected, to stop zip bombs
+pub const MAX_UNPACKED_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Archives with more entries are rejected
+pub const MAX_ENTRIES: usize = 10_000;
+
+const ZIP_TYPES: [&str; 3] = [
+    "application/zip",
+    "application/x-zip-compressed",
+    "application/x-zip",
+];
+
+const TAR_GZ_TYPES: [&str; 4] = [
+    "application/gzip",
+    "application/x-gzip",
+    "application/x-compressed-tar",
+    "application/x-gtar",
+];
+
+/// File contained in an archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnpackedFile {
+    /// Name without the folders inside the archive
+    pub file_name: String,
+    pub mimetype: String,
+    pub body: Vec<u8>,
+}
+
+/// Returns true for the mimetypes of the supported archives
+pub fn is_archive(mimetype: &str) -> bool {
+    ZIP_TYPES
+        .iter()
+        .chain(TAR_GZ_TYPES.iter())
+        .any(|x| x.eq_ignore_ascii_case(mimetype))
+}
+
+/// Returns the files of the accepted mimetypes in the archive. Folders and
+/// files of other types are skipped. Encrypted entries without the right
+/// password fail with a [`PasswordError`].
+pub fn unpack(
+    mimetype: &str,
+    body: &[u8],
+    accepted: &MimeArguments,
+    password: Option<&str>,
+) -> Result<Vec<UnpackedFile>> {
+    let entries = if ZIP_TYPES.iter().any(|x| x.eq_ignore_ascii_case(mimetype)) {
+        unpack_zip(body, password)?
+    } else if TAR_GZ_TYPES
+        .iter()
+        .any(|x| x.eq_ignore_ascii_case(mimetype))
+    {
+        unpack_tar_gz(body)?
+    } else {
+        bail!("Unsupported archive type {}", mimetype);
+    };
+    let mut rv = Vec::new();
+    for (path, body) in entries {
+        let file_name = match path.rsplit('/').next() {
+            Some(x) if !x.is_empty() => x.to_owned(),
+            _ => continue,
+        };
+        let mut mimetype = crate::mimetype_from_file_name(&file_name);
+        if mimetype == "application/octet-stream" {
+            if let Some(detected) = sniff::detect(&body[..body.len().min(sniff::SNIFF_LENGTH)]) {
+                mimetype = detected.to_owned();
+            }
+        }
+        if !accepted.0.contains(&mimetype) {
+            log::debug!("Skipping {} of type {} in archive", &path, &mimetype);
+            continue;
+        }
+        rv.push(UnpackedFile {
+            file_name,
+            mimetype,
+            body,
+        });
+    }
+    Ok(rv)
+}
+
+/// Counts the unpacked bytes of all entries
+#[derive(Default)]
+struct Limits {
+    entries: usize,
+    size: u64,
+}
+
+impl Limits {
+    /// Reads the entry, failing if the archive exceeds the limits
+    fn read(&mut self, entry: impl Read) -> Result<Vec<u8>> {
+        self.entries += 1;
+        if self.entries > MAX_ENTRIES {
+            bail!("Archive has more than {} entries", MAX_ENTRIES);
+        }
+        let mut body = Vec::new();
+        // one byte more to notice a too large entry
+        let remaining = MAX_UNPACKED_SIZE - self.size;
+        entry.take(remaining + 1).read_to_end(&mut body)?;
+        self.size += body.len() as u64;
+        if self.size > MAX_UNPACKED_SIZE {
+            bail!("Archive unpacks to more than {} bytes", MAX_UNPACKED_SIZE);
+        }
+        Ok(body)
+    }
+}
+
+fn unpack_zip(body: &[u8], password: Option<&str>) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(body)).context("Can't open zip archive")?;
+    let mut limits = Limits::default();
+    let mut rv = Vec::new();
+    for index in 0..archive.len() {
+        let entry = match password {
+            Some(password) => archive
+                .by_index_decrypt(index, password.as_bytes())
+                .map(|x| x.map_err(|_| PasswordError::Wrong)),
+            None => match archive.by_index(index) {
+                Err(zip::result::ZipError::UnsupportedArchive(
+                    zip::result::ZipError::PASSWORD_REQUIRED,
+                )) => Ok(Err(PasswordError::Missing)),
+                other => other.map(Ok),
+            },
+        };
+        let entry =
+            entry.with_context(|| format!("Can't read entry {} of zip archive", index))??;
+        if entry.is_dir() {
+            continue;
+        }
+        let path = entry.name().to_owned();
+        let content = limits
+            .read(entry)
+            .with_context(|| format!("Can't unpack {}", &path))?;
+        rv.push((path, content));
+    }
+    Ok(rv)
+}
+
+fn unpack_tar_gz(body: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(body));
+    let mut limits = Limits::default();
+    let mut rv = Vec::new();
+    for entry in archive.entries().context("Can't open tar archive")? {
+        let entry = entry.context("Can't read entry of tar archive")?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let content = limits
+            .read(entry)
+            .with_context(|| format!("Can't unpack {}", &path))?;
+        rv.push((path, content));
+    }
+    Ok(rv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_unpack_zip() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default();
+        writer.add_directory("2023/", options).unwrap();
+        writer.start_file("2023/invoice.pdf", options).unwrap();
+        writer.write_all(b"%PDF-1.4").unwrap();
+        writer.start_file("readme.txt", options).unwrap();
+        writer.write_all(b"see invoice").unwrap();
+        writer.start_file("scan", options).unwrap();
+        writer.write_all(b"%PDF-1.5").unwrap();
+        let body = writer.finish().unwrap().into_inner();
+
+        assert!(is_archive("application/zip"));
+        let files = unpack("application/zip", &body, &MimeArguments::default(), None).unwrap();
+        let names: Vec<_> = files.iter().map(|x| x.file_name.as_str()).collect();
+        assert_eq!(names, vec!["invoice.pdf", "scan"]);
+        assert_eq!(files[0].mimetype, "application/pdf");
+        assert_eq!(files[1].body, b"%PDF-1.5");
+
+        let broken = unpack(
+            "application/zip",
+            b"PK\x03\x04",
+            &MimeArguments::default(),
+            None,
+        );
+        assert!(broken.is_err());
+    }
+}
@@ -0,0 +1,694 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Extraction of attachments as async stream.
+//!
+//! The stream is independent from the storage backends, so embedders can
+//! route the extracted bytes wherever they want:
+//!
+//! ```no_run
+//! # async fn example(raw: &[u8]) -> anyhow::Result<()> {
+//! use futures::StreamExt;
+//! use tokio::io::AsyncReadExt;
+//!
+//! let parsed = mailparse::parse_mail(raw)?;
+//! let accepted = invoice2storage::MimeArguments::default();
+//! let stream = invoice2storage::attachments::attachments(&parsed, &accepted);
+//! futures::pin_mut!(stream);
+//! while let Some(attachment) = stream.next().await {
+//!     let mut attachment = attachment?;
+//!     let mut body = Vec::new();
+//!     attachment.body.read_to_end(&mut body).await?;
+//!     println!("{}: {} bytes", attachment.info.file_name, body.len());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{archives, convert, invoice, sniff, Config, MimeArguments};
+use anyhow::{Context, Result};
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine;
+use bytes::Bytes;
+use futures::stream::{self, Stream};
+use mailparse::{body::Body, DispositionType, ParsedMail};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+
+/// Number of base64 symbols decoded at once
+const DECODE_CHUNK: usize = 64 * 1024;
+
+/// Forwarded mails nested deeper are not searched for attachments
+const MAX_FORWARD_DEPTH: usize = 8;
+
+/// Mail clients often omit the padding or leave trailing bits
+const MIME_BASE64: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::STANDARD,
+    GeneralPurposeConfig::new()
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent)
+        .with_decode_allow_trailing_bits(true),
+);
+
+/// Metadata of an extracted attachment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttachmentInfo {
+    /// File name from the content disposition or a generated one
+    pub file_name: String,
+    pub mimetype: String,
+    /// Size of the decoded body in bytes
+    pub size: usize,
+}
+
+/// Extracted attachment with a reader for the decoded body
+pub struct ExtractedAttachment<'a> {
+    pub info: AttachmentInfo,
+    pub body: Pin<Box<dyn AsyncRead + Send + Sync + 'a>>,
+}
+
+impl std::fmt::Debug for ExtractedAttachment<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExtractedAttachment")
+            .field("info", &self.info)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Decoded attachment body, kept in memory or spilled to a temporary file
+#[derive(Debug, Clone)]
+pub enum AttachmentBody {
+    Memory(Bytes),
+    /// Body larger than the memory limit. The temporary file is removed
+    /// when the last clone is dropped.
+    Spilled(Arc<SpilledBody>),
+}
+
+/// Temporary file with the decoded body, mapped into memory
+#[derive(Debug)]
+pub struct SpilledBody {
+    file: tempfile::NamedTempFile,
+    map: memmap2::Mmap,
+}
+
+impl AttachmentBody {
+    /// Reads the body into memory, or into a temporary file if the size
+    /// exceeds the memory limit
+    pub async fn read<R>(reader: &mut R, size: usize, memory_limit: Option<usize>) -> Result<Self>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        match memory_limit {
+            Some(limit) if size > limit => {
+                let file = tempfile::NamedTempFile::new().context("Can't create temporary file")?;
+                let mut writer = tokio::fs::File::from_std(file.reopen()?);
+                let written = tokio::io::copy(reader, &mut writer).await?;
+                writer.flush().await?;
+                if written == 0 {
+                    // empty files can't be mapped
+                    return Ok(AttachmentBody::Memory(Bytes::new()));
+                }
+                // safety: the temporary file is owned by this process and
+                // not modified while it is mapped
+                let map = unsafe { memmap2::Mmap::map(file.as_file())? };
+                log::debug!("Spilled {} bytes to {}", written, file.path().display());
+                Ok(AttachmentBody::Spilled(Arc::new(SpilledBody { file, map })))
+            }
+            _ => {
+                let mut body = Vec::with_capacity(size);
+                reader.read_to_end(&mut body).await?;
+                Ok(AttachmentBody::Memory(body.into()))
+            }
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            AttachmentBody::Memory(bytes) => bytes,
+            AttachmentBody::Spilled(spilled) => &spilled.map,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Path of the temporary file, if the body was spilled to disk
+    pub fn spilled_path(&self) -> Option<&std::path::Path> {
+        match self {
+            AttachmentBody::Memory(_) => None,
+            AttachmentBody::Spilled(spilled) => Some(spilled.file.path()),
+        }
+    }
+}
+
+/// Decodes a base64 body while it is read, without materializing the
+/// whole decoded body
+struct Base64Reader<'a> {
+    encoded: &'a [u8],
+    /// Symbols without line breaks, waiting for decoding
+    symbols: Vec<u8>,
+    decoded: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a> Base64Reader<'a> {
+    fn new(encoded: &'a [u8]) -> Self {
+        Self {
+            encoded,
+            symbols: Vec::new(),
+            decoded: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Size of the decoded body
+    fn decoded_size(&self) -> usize {
+        let symbols = self
+            .encoded
+            .iter()
+            .filter(|x| !x.is_ascii_whitespace() && **x != b'=')
+            .count();
+        symbols * 3 / 4
+    }
+
+    /// Decodes the next chunk. Only complete quadruples are decoded until
+    /// the end of the input is reached.
+    fn fill(&mut self) -> std::io::Result<()> {
+        let mut taken = 0;
+        for byte in self.encoded.iter() {
+            if self.symbols.len() >= DECODE_CHUNK {
+                break;
+            }
+            taken += 1;
+            if !byte.is_ascii_whitespace() {
+                self.symbols.push(*byte);
+            }
+        }
+        self.encoded = &self.encoded[taken..];
+        let usable = if self.encoded.is_empty() {
+            self.symbols.len()
+        } else {
+            self.symbols.len() / 4 * 4
+        };
+        self.decoded.clear();
+        self.pos = 0;
+        MIME_BASE64
+            .decode_vec(&self.symbols[..usable], &mut self.decoded)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        self.symbols.drain(..usable);
+        Ok(())
+    }
+}
+
+impl AsyncRead for Base64Reader<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        while this.pos >= this.decoded.len() {
+            if this.encoded.is_empty() && this.symbols.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            if let Err(err) = this.fill() {
+                return Poll::Ready(Err(err));
+            }
+        }
+        let len = buf.remaining().min(this.decoded.len() - this.pos);
+        buf.put_slice(&this.decoded[this.pos..this.pos + len]);
+        this.pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Parts extracted in addition to the attachments
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExtractOptions {
+    /// Parts with an inline disposition
+    pub accept_inline: bool,
+    /// Parts of other types whose content is of an accepted type, the
+    /// detected type replaces the declared one
+    pub sniff_content: bool,
+    /// Archives, their files are unpacked by the caller, see
+    /// [`crate::archives`]
+    pub unpack_archives: bool,
+    /// Images, they are converted by the caller, see [`crate::convert`]
+    pub convert_images: bool,
+    /// XML parts, the caller keeps the detected invoices, see
+    /// [`crate::invoice`]
+    pub xml_invoices: bool,
+}
+
+impl ExtractOptions {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            accept_inline: config.accept_inline,
+            sniff_content: config.sniff_content,
+            unpack_archives: config.unpack_archives,
+            convert_images: config.convert_images_to_pdf,
+            xml_invoices: true,
+        }
+    }
+}
+
+/// Attachment found in the MIME tree
+enum Found<'a> {
+    Part {
+        part: &'a ParsedMail<'a>,
+        mimetype: String,
+    },
+    /// Attachment of a forwarded mail, decoded together with the mail
+    Forwarded {
+        file_name: Option<String>,
+        mimetype: String,
+        body: Vec<u8>,
+    },
+    /// A forwarded mail couldn't be decoded
+    Failed(anyhow::Error),
+}
+
+/// Returns the mimetype of the part if it is extracted
+fn attachment_type(
+    part: &ParsedMail,
+    accepted: &MimeArguments,
+    options: ExtractOptions,
+) -> Option<String> {
+    let accepted = |mimetype: &str| {
+        accepted.0.iter().any(|x| x == mimetype)
+            || (options.unpack_archives && archives::is_archive(mimetype))
+            || (options.convert_images && convert::is_convertible(mimetype))
+            || (options.xml_invoices && invoice::is_xml(mimetype))
+    };
+    let extracted = match part.get_content_disposition().disposition {
+        DispositionType::Attachment => true,
+        DispositionType::Inline => options.accept_inline,
+        _ => false,
+    };
+    if !extracted {
+        return None;
+    }
+    if accepted(&part.ctype.mimetype) {
+        return Some(part.ctype.mimetype.clone());
+    }
+    let container =
+        !part.subparts.is_empty() || part.ctype.mimetype.eq_ignore_ascii_case("message/rfc822");
+    if !options.sniff_content || container {
+        return None;
+    }
+    let detected = sniff::detect(&body_head(part))?;
+    if !accepted(detected) {
+        return None;
+    }
+    log::debug!(
+        "Attachment declared as {} is {}",
+        &part.ctype.mimetype,
+        detected
+    );
+    Some(detected.to_owned())
+}
+
+/// Start of the decoded body, base64 bodies are only decoded as far as needed
+fn body_head(part: &ParsedMail) -> Vec<u8> {
+    let mut head = match part.get_body_encoded() {
+        Body::Base64(encoded) => {
+            // 4 symbols for 3 bytes, line breaks included
+            let raw = encoded.get_raw();
+            let encoded = &raw[..raw.len().min(sniff::SNIFF_LENGTH * 2)];
+            let symbols: Vec<u8> = encoded
+                .iter()
+                .filter(|x| !x.is_ascii_whitespace())
+                .copied()
+                .collect();
+            let usable = if encoded.len() == raw.len() {
+                symbols.len()
+            } else {
+                symbols.len() / 4 * 4
+            };
+            MIME_BASE64.decode(&symbols[..usable]).unwrap_or_default()
+        }
+        _ => part.get_body_raw().unwrap_or_default(),
+    };
+    head.truncate(sniff::SNIFF_LENGTH);
+    head
+}
+
+fn file_name(part: &ParsedMail) -> Option<String> {
+    part.get_content_disposition()
+        .params
+        .get("filename")
+        .cloned()
+}
+
+/// Collects the attachments of the whole MIME tree in document order.
+/// Multiparts are searched at any depth, forwarded mails up to
+/// [`MAX_FORWARD_DEPTH`].
+fn walk<'a>(
+    part: &'a ParsedMail<'a>,
+    accepted: &MimeArguments,
+    options: ExtractOptions,
+    depth: usize,
+    found: &mut Vec<Found<'a>>,
+) {
+    if let Some(mimetype) = attachment_type(part, accepted, options) {
+        found.push(Found::Part { part, mimetype });
+    } else if part.ctype.mimetype.eq_ignore_ascii_case("message/rfc822") {
+        if depth < MAX_FORWARD_DEPTH {
+            found.extend(forwarded(part, accepted, options, depth + 1));
+        } else {
+            log::warn!("Forwarded mails nested too deep, skipping");
+        }
+    } else {
+        for subpart in part.subparts.iter() {
+            walk(subpart, accepted, options, depth, found);
+        }
+    }
+}
+
+/// Attachments of a forwarded mail. The mail is parsed from the decoded
+/// part, so its attachments are decoded right away.
+fn forwarded(
+    part: &ParsedMail,
+    accepted: &MimeArguments,
+    options: ExtractOptions,
+    depth: usize,
+) -> Vec<Found<'static>> {
+    let failed = |err: mailparse::MailParseError, context: &'static str| {
+        vec![Found::Failed(anyhow::Error::new(err).context(context))]
+    };
+    let raw = match part.get_body_raw() {
+        Ok(x) => x,
+        Err(err) => return failed(err, "Can't decode forwarded mail"),
+    };
+    let nested = match mailparse::parse_mail(&raw) {
+        Ok(x) => x,
+        Err(err) => return failed(err, "Can't parse forwarded mail"),
+    };
+    let mut found = Vec::new();
+    walk(&nested, accepted, options, depth, &mut found);
+    found
+        .into_iter()
+        .map(|x| match x {
+            Found::Part { part, mimetype } => match part.get_body_raw() {
+                Ok(body) => Found::Forwarded {
+                    file_name: file_name(part),
+                    mimetype,
+                    body,
+                },
+                Err(err) => Found::Failed(
+                    anyhow::Error::new(err).context("Can't get body of forwarded attachment"),
+                ),
+            },
+            Found::Forwarded {
+                file_name,
+                mimetype,
+                body,
+            } => Found::Forwarded {
+                file_name,
+                mimetype,
+                body,
+            },
+            Found::Failed(err) => Found::Failed(err),
+        })
+        .collect()
+}
+
+/// Returns a stream of all attachments matching the accepted mimetypes,
+/// including the ones in nested multiparts and forwarded mails.
+/// Base64 bodies are decoded while they are read, other encodings when
+/// the stream is polled. Parts are selected by their headers, only the
+/// start of the body is decoded when the content is sniffed.
+pub fn attachments<'a>(
+    parsed: &'a ParsedMail<'a>,
+    accepted: &'a MimeArguments,
+) -> impl Stream<Item = Result<ExtractedAttachment<'a>>> + 'a {
+    attachments_with_options(parsed, accepted, ExtractOptions::default())
+}
+
+/// Like [`attachments`], with the additionally extracted parts
+pub fn attachments_with_options<'a>(
+    parsed: &'a ParsedMail<'a>,
+    accepted: &'a MimeArguments,
+    options: ExtractOptions,
+) -> impl Stream<Item = Result<ExtractedAttachment<'a>>> + 'a {
+    let mut found = Vec::new();
+    walk(parsed, accepted, options, 0, &mut found);
+    let mut unknown = 0;
+    let mut generated_name = move || {
+        unknown += 1;
+        format!("attachment-{}", unknown)
+    };
+    stream::iter(found.into_iter().map(move |found| {
+        let (subpart, mimetype) = match found {
+            Found::Part { part, mimetype } => (part, mimetype),
+            Found::Forwarded {
+                file_name,
+                mimetype,
+                body,
+            } => {
+                return Ok(ExtractedAttachment {
+                    info: AttachmentInfo {
+                        file_name: file_name.unwrap_or_else(&mut generated_name),
+                        mimetype,
+                        size: body.len(),
+                    },
+                    body: Box::pin(std::io::Cursor::new(body)),
+                });
+            }
+            Found::Failed(err) => return Err(err),
+        };
+        let file_name = file_name(subpart).unwrap_or_else(&mut generated_name);
+        if let Body::Base64(encoded) = subpart.get_body_encoded() {
+            let reader = Base64Reader::new(encoded.get_raw());
+            return Ok(ExtractedAttachment {
+                info: AttachmentInfo {
+                    file_name,
+                    mimetype,
+                    size: reader.decoded_size(),
+                },
+                body: Box::pin(reader),
+            });
+        }
+        let body = subpart
+            .get_body_raw()
+            .with_context(|| format!("Can't get body of attachment {}", &file_name))?;
+        Ok(ExtractedAttachment {
+            info: AttachmentInfo {
+                file_name,
+                mimetype,
+                size: body.len(),
+            },
+            body: Box::pin(std::io::Cursor::new(body)),
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_attachment_stream() {
+        let raw = std::fs::read("test-data/test_email1.eml").unwrap();
+        let parsed = mailparse::parse_mail(&raw).unwrap();
+        let accepted = MimeArguments::default();
+        let stream = attachments(&parsed, &accepted);
+        futures::pin_mut!(stream);
+
+        let mut attachment = stream.next().await.unwrap().unwrap();
+        assert_eq!(attachment.info.file_name, "sample1.pdf");
+        assert_eq!(attachment.info.mimetype, "application/pdf");
+        let mut body = Vec::new();
+        attachment.body.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body.len(), attachment.info.size);
+        assert!(body.starts_with(b"%PDF"));
+        assert!(stream.next().await.is_none());
+        // compare with the non streaming decoder of mailparse
+        assert_eq!(body, parsed.subparts[1].get_body_raw().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_skipped_parts_not_decoded() {
+        // the html part is not valid base64 and would fail if decoded
+        let raw = concat!(
+            "Content-Type: multipart/mixed; boundary=\"b\"\r\n",
+            "\r\n",
+            "--b\r\n",
+            "Content-Type: text/html\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "<html>not base64!</html>\r\n",
+            "--b\r\n",
+            "Content-Type: application/pdf\r\n",
+            "Content-Disposition: attachment; filename=\"invoice.pdf\"\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "JVBERi0xLjQ=\r\n",
+            "--b--\r\n",
+        );
+        let parsed = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        let accepted = MimeArguments::default();
+        let stream = attachments(&parsed, &accepted);
+        futures::pin_mut!(stream);
+
+        let mut attachment = stream.next().await.unwrap().unwrap();
+        assert_eq!(attachment.info.file_name, "invoice.pdf");
+        let mut body = Vec::new();
+        attachment.body.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, b"%PDF-1.4");
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_nested_attachments() {
+        let raw = concat!(
+            "Content-Type: multipart/alternative; boundary=\"outer\"\r\n",
+            "\r\n",
+            "--outer\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "see attachment\r\n",
+            "--outer\r\n",
+            "Content-Type: multipart/mixed; boundary=\"inner\"\r\n",
+            "\r\n",
+            "--inner\r\n",
+            "Content-Type: application/pdf\r\n",
+            "Content-Disposition: attachment; filename=\"nested.pdf\"\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "JVBERi0xLjQ=\r\n",
+            "--inner\r\n",
+            "Content-Type: message/rfc822\r\n",
+            "\r\n",
+            "Subject: Fwd: invoice\r\n",
+            "Content-Type: multipart/mixed; boundary=\"fwd\"\r\n",
+            "\r\n",
+            "--fwd\r\n",
+            "Content-Type: application/pdf\r\n",
+            "Content-Disposition: attachment; filename=\"forwarded.pdf\"\r\n",
+            "\r\n",
+            "%PDF-1.5\r\n",
+            "--fwd--\r\n",
+            "--inner--\r\n",
+            "--outer--\r\n",
+        );
+        let parsed = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        let accepted = MimeArguments::default();
+        let stream = attachments(&parsed, &accepted);
+        futures::pin_mut!(stream);
+
+        let mut names = Vec::new();
+        while let Some(attachment) = stream.next().await {
+            let mut attachment = attachment.unwrap();
+            let mut body = Vec::new();
+            attachment.body.read_to_end(&mut body).await.unwrap();
+            assert!(body.starts_with(b"%PDF"));
+            names.push(attachment.info.file_name);
+        }
+        assert_eq!(names, vec!["nested.pdf", "forwarded.pdf"]);
+    }
+
+    #[test]
+    fn test_inline_attachments() {
+        let raw = concat!(
+            "Content-Type: multipart/mixed; boundary=\"b\"\r\n",
+            "\r\n",
+            "--b\r\n",
+            "Content-Type: application/pdf\r\n",
+            "Content-Disposition: inline; filename=\"invoice.pdf\"\r\n",
+            "\r\n",
+            "%PDF-1.4\r\n",
+            "--b--\r\n",
+        );
+        let parsed = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        let accepted = MimeArguments::default();
+        let count = |options| {
+            let stream = attachments_with_options(&parsed, &accepted, options);
+            futures::executor::block_on(stream.count())
+        };
+        assert_eq!(count(ExtractOptions::default()), 0);
+        let options = ExtractOptions {
+            accept_inline: true,
+            ..Default::default()
+        };
+        assert_eq!(count(options), 1);
+    }
+
+    #[tokio::test]
+    async fn test_sniffed_attachments() {
+        let raw = concat!(
+            "Content-Type: multipart/mixed; boundary=\"b\"\r\n",
+            "\r\n",
+            "--b\r\n",
+            "Content-Type: application/octet-stream\r\n",
+            "Content-Disposition: attachment; filename=\"scan\"\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "JVBERi0xLjQ=\r\n",
+            "--b\r\n",
+            "Content-Type: application/octet-stream\r\n",
+            "Content-Disposition: attachment; filename=\"data.bin\"\r\n",
+            "\r\n",
+            "not a document\r\n",
+            "--b--\r\n",
+        );
+        let parsed = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        let accepted = MimeArguments::default();
+        let stream = attachments(&parsed, &accepted);
+        futures::pin_mut!(stream);
+        assert!(stream.next().await.is_none());
+
+        let options = ExtractOptions {
+            sniff_content: true,
+            ..Default::default()
+        };
+        let stream = attachments_with_options(&parsed, &accepted, options);
+        futures::pin_mut!(stream);
+        let mut attachment = stream.next().await.unwrap().unwrap();
+        assert_eq!(attachment.info.file_name, "scan");
+        assert_eq!(attachment.info.mimetype, "application/pdf");
+        let mut body = Vec::new();
+        attachment.body.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, b"%PDF-1.4");
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_spilled_body() {
+        let data = b"%PDF-1.4 large scan".to_vec();
+        let mut reader = std::io::Cursor::new(data.clone());
+        let body = AttachmentBody::read(&mut reader, data.len(), Some(4))
+            .await
+            .unwrap();
+        let path = body.spilled_path().unwrap().to_owned();
+        assert!(path.exists());
+        assert_eq!(body.as_slice(), data.as_slice());
+        drop(body);
+        assert!(!path.exists());
+
+        let mut reader = std::io::Cursor::new(data.clone());
+        let body = AttachmentBody::read(&mut reader, data.len(), None)
+            .await
+            .unwrap();
+        assert!(body.spilled_path().is_none());
+        assert_eq!(body.len(), data.len());
+    }
+
+    #[tokio::test]
+    async fn test_base64_reader() {
+        // line breaks inside a quadruple and missing padding
+        let encoded = b"aW52b2lj\r\nZTJzdG9yYWd\r\nlIQ\r\n";
+        let mut reader = Base64Reader::new(encoded);
+        assert_eq!(reader.decoded_size(), 16);
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, b"invoice2storage!");
+    }
+}
@@ -0,0 +1,332 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Tamper-evident record of all processed messages.
+//!
+//! Every line of the audit log is a JSON record containing the hash of the
+//! previous record, so changing or removing a record breaks the chain from
+//! that point on. With a signing key the head of the chain is signed every
+//! `audit_sign_interval` records, so the log can't be rewritten as a whole
+//! without the key either. `audit verify` checks both.
+
+use crate::ProcessResult;
+use anyhow::{anyhow, bail, Context, Result};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Previous hash of the first record
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Bytes read from the end of the log to find the last record
+const TAIL_SIZE: u64 = 64 * 1024;
+
+/// One line of the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AuditRecord {
+    pub seq: u64,
+    /// Unix timestamp
+    pub time: u64,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+    /// Hash of the previous record
+    pub prev: String,
+    /// SHA-256 of the record with an empty hash
+    #[serde(default)]
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A processed message or document
+    Message {
+        message: Option<String>,
+        user: Option<String>,
+        files: Vec<String>,
+        success: bool,
+        errors: Vec<String>,
+    },
+    /// Ed25519 signature of `prev`
+    Signature {
+        public_key: String,
+        signature: String,
+    },
+}
+
+impl AuditRecord {
+    fn compute_hash(&self) -> Result<String> {
+        let mut record = self.clone();
+        record.hash = String::new();
+        Ok(hex(&Sha256::digest(serde_json::to_vec(&record)?)))
+    }
+}
+
+/// Appends records to the audit log. Appends are serialized with a file
+/// lock, so several processes, like MTA pipe filters, can share the log.
+pub struct AuditLog {
+    path: PathBuf,
+    signer: Option<Ed25519KeyPair>,
+    sign_interval: u64,
+}
+
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("path", &self.path)
+            .field("signed", &self.signer.is_some())
+            .finish()
+    }
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            signer: None,
+            sign_interval: 100,
+        }
+    }
+
+    /// Signs the head of the chain every `interval` records with the
+    /// Ed25519 key, a PKCS#8 file created by `audit keygen`
+    pub fn with_signing_key(mut self, key_file: &Path, interval: u64) -> Result<Self> {
+        let pkcs8 = std::fs::read(key_file)
+            .with_context(|| format!("Can't read audit key {}", key_file.display()))?;
+        let signer = Ed25519KeyPair::from_pkcs8(&pkcs8)
+            .map_err(|e| anyhow!("Invalid audit key {}: {}", key_file.display(), e))?;
+        self.signer = Some(signer);
+        self.sign_interval = interval.max(1);
+        Ok(self)
+    }
+
+    /// Records the result of a message
+    pub fn append(&self, result: &ProcessResult) -> Result<()> {
+        let event = AuditEvent::Message {
+            message: result.message.clone(),
+            user: result.user.clone(),
+            files: result.files.clone(),
+            success: result.is_success(),
+            errors: result.errors.iter().map(|x| x.message.clone()).collect(),
+        };
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .with_context(|| format!("Can't open audit log {}", self.path.display()))?;
+        lock(&file)?;
+        let last = last_record(&mut file)?;
+        let record = write_record(&mut file, last.as_ref(), event)?;
+        if let Some(signer) = &self.signer {
+            if record.seq % self.sign_interval == 0 {
+                let signature = signer.sign(record.hash.as_bytes());
+                let event = AuditEvent::Signature {
+                    public_key: hex(signer.public_key().as_ref()),
+                    signature: hex(signature.as_ref()),
+                };
+                write_record(&mut file, Some(&record), event)?;
+            }
+        }
+        file.sync_data()?;
+        // the lock is released when the file is closed
+        Ok(())
+    }
+}
+
+fn write_record(
+    file: &mut File,
+    last: Option<&AuditRecord>,
+    event: AuditEvent,
+) -> Result<AuditRecord> {
+    let mut record = AuditRecord {
+        seq: last.map(|x| x.seq + 1).unwrap_or(1),
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default(),
+        event,
+        prev: last
+            .map(|x| x.hash.clone())
+            .unwrap_or_else(|| GENESIS_HASH.to_owned()),
+        hash: String::new(),
+    };
+    record.hash = record.compute_hash()?;
+    let mut line = serde_json::to_vec(&record)?;
+    line.push(b'\n');
+    file.write_all(&line).context("Can't write audit log")?;
+    Ok(record)
+}
+
+/// Reads the last record from the end of the file
+fn last_record(file: &mut File) -> Result<Option<AuditRecord>> {
+    let len = file.metadata()?.len();
+    let start = len.saturating_sub(TAIL_SIZE);
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    // the chunk may start inside a character of an earlier record
+    let tail = String::from_utf8_lossy(&tail);
+    let line = match tail.lines().rev().find(|x| !x.trim().is_empty()) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let record = serde_json::from_str(line).context("Last audit record is damaged")?;
+    Ok(Some(record))
+}
+
+/// Locks the file exclusively until it is closed
+#[cfg(unix)]
+pub(crate) fn lock(file: &File) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: the file descriptor is valid while the file is open
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        bail!("Can't lock file: {}", std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn lock(_file: &File) -> Result<()> {
+    Ok(())
+}
+
+/// Result of [`verify`]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Verification {
+    pub records: u64,
+    pub signatures: u64,
+    /// Records after the last signature
+    pub unsigned: u64,
+}
+
+/// Checks the hash chain and, with a public key, the signatures of the
+/// log. Fails on the first broken record.
+pub fn verify(path: &Path, public_key: Option<&str>) -> Result<Verification> {
+    let file =
+        File::open(path).with_context(|| format!("Can't open audit log {}", path.display()))?;
+    let mut rv = Verification::default();
+    let mut prev = GENESIS_HASH.to_owned();
+    let mut seq = 0;
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let number = number + 1;
+        let record: AuditRecord = serde_json::from_str(&line)
+            .with_context(|| format!("Line {}: invalid record", number))?;
+        if record.prev != prev {
+            bail!(
+                "Line {}: chain broken, a record before was changed or removed",
+                number
+            );
+        }
+        if record.seq != seq + 1 {
+            bail!(
+                "Line {}: expected record {}, found {}",
+                number,
+                seq + 1,
+                record.seq
+            );
+        }
+        if record.compute_hash()? != record.hash {
+            bail!("Line {}: record was modified", number);
+        }
+        if let AuditEvent::Signature {
+            public_key: signer,
+            signature,
+        } = &record.event
+        {
+            if let Some(public_key) = public_key {
+                if !signer.eq_ignore_ascii_case(public_key) {
+                    bail!("Line {}: signed with an unknown key {}", number, signer);
+                }
+                UnparsedPublicKey::new(&ED25519, unhex(public_key)?)
+                    .verify(record.prev.as_bytes(), &unhex(signature)?)
+                    .map_err(|_| anyhow!("Line {}: invalid signature", number))?;
+            }
+            rv.signatures += 1;
+            rv.unsigned = 0;
+        } else {
+            rv.unsigned += 1;
+        }
+        rv.records += 1;
+        prev = record.hash;
+        seq = record.seq;
+    }
+    Ok(rv)
+}
+
+/// Creates a new Ed25519 signing key and returns the public key
+pub fn generate_key(key_file: &Path) -> Result<String> {
+    let rng = ring::rand::SystemRandom::new();
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).map_err(|_| anyhow!("Can't generate key"))?;
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(key_file)
+        .with_context(|| format!("Can't create {}", key_file.display()))?
+        .write_all(pkcs8.as_ref())?;
+    let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).map_err(|e| anyhow!("{}", e))?;
+    Ok(hex(key_pair.public_key().as_ref()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn unhex(text: &str) -> Result<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        bail!("Invalid hex string");
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).context("Invalid hex string"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let key_file = dir.path().join("audit.key");
+        let public_key = generate_key(&key_file).unwrap();
+        let log = AuditLog::new(path.clone())
+            .with_signing_key(&key_file, 2)
+            .unwrap();
+        for i in 0..3 {
+            let mut result = ProcessResult::default();
+            result.message = Some(format!("mail{}.eml", i));
+            result.files = vec![format!("office/invoice{}.pdf", i)];
+            log.append(&result).unwrap();
+        }
+        let verification = verify(&path, Some(&public_key)).unwrap();
+        assert_eq!(
+            verification,
+            Verification {
+                records: 4,
+                signatures: 1,
+                unsigned: 1,
+            }
+        );
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, content.replacen("invoice1.pdf", "other.pdf", 1)).unwrap();
+        let err = verify(&path, None).unwrap_err();
+        assert!(err.to_string().contains("Line 2"), "{}", err);
+    }
+}
@@ -0,0 +1,106 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Compression of stored messages, sidecars and XML documents.
+//!
+//! Archives of high-volume installations are mostly text. With
+//! `--compress gzip` or `--compress zstd` the original messages of
+//! `--store-eml`, the metadata sidecars and XML attachments are compressed
+//! before they are stored and `.gz` or `.zst` is appended to their rendered
+//! path:
+//!
+//! ```toml
+//! store_eml = true
+//! compress = "zstd"
+//! ```
+//!
+//! PDFs and images are compressed already and stored as they are.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+/// zstd level of the command line tool
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression of the stored text files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Store the files as they are
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Extension appended to the rendered path
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::Gzip => ".gz",
+            Self::Zstd => ".zst",
+        }
+    }
+
+    /// Path of the compressed file
+    pub fn path(&self, path: &str) -> String {
+        format!("{}{}", path, self.extension())
+    }
+
+    pub fn compress(&self, body: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::None => Ok(body.to_vec()),
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                Ok(encoder.finish()?)
+            }
+            Self::Zstd => Ok(zstd::encode_all(body, ZSTD_LEVEL)?),
+        }
+    }
+}
+
+/// True for XML documents, the attachments that are compressed
+pub fn is_compressible(mimetype: &str) -> bool {
+    let mimetype = mimetype
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    mimetype == "application/xml" || mimetype == "text/xml" || mimetype.ends_with("+xml")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_compression() {
+        let body = b"<Invoice>".repeat(100);
+        assert_eq!(Compression::None.compress(&body).unwrap(), body);
+        assert_eq!(Compression::None.path("a/invoice.xml"), "a/invoice.xml");
+
+        let gzip = Compression::Gzip.compress(&body).unwrap();
+        assert!(gzip.len() < body.len());
+        let mut plain = Vec::new();
+        flate2::read::GzDecoder::new(&gzip[..])
+            .read_to_end(&mut plain)
+            .unwrap();
+        assert_eq!(plain, body);
+        assert_eq!(Compression::Gzip.path("a/invoice.xml"), "a/invoice.xml.gz");
+
+        let zstd = Compression::Zstd.compress(&body).unwrap();
+        assert_eq!(zstd::decode_all(&zstd[..]).unwrap(), body);
+        assert_eq!(Compression::Zstd.path("a/1.eml"), "a/1.eml.zst");
+
+        assert!(is_compressible("application/xml"));
+        assert!(is_compressible("Text/XML; charset=utf-8"));
+        assert!(is_compressible("application/vnd.ubl+xml"));
+        assert!(!is_compressible("application/pdf"));
+    }
+}
@@ -0,0 +1,198 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Conversion of image attachments to PDF.
+//!
+//! With `--convert-images-to-pdf` photos of receipts are wrapped into a
+//! single page PDF before they are stored, so the archive only contains
+//! PDFs. JPEGs are embedded as they are, other images are decoded and
+//! compressed losslessly.
+
+use anyhow::{bail, Context, Result};
+use lopdf::{dictionary, Document, Object, Stream};
+use std::io::Write;
+use std::path::Path;
+
+/// Length of the longer side of the page in points, the length of A4
+const PAGE_LENGTH: f64 = 842.0;
+
+const IMAGE_TYPES: [&str; 6] = [
+    "image/jpeg",
+    "image/png",
+    "image/gif",
+    "image/tiff",
+    "image/bmp",
+    "image/webp",
+];
+
+/// Returns true for the image types that can be converted
+pub fn is_convertible(mimetype: &str) -> bool {
+    IMAGE_TYPES.iter().any(|x| x.eq_ignore_ascii_case(mimetype))
+}
+
+/// File name of the converted image
+pub fn pdf_file_name(file_name: &str) -> String {
+    let stem = Path::new(file_name)
+        .file_stem()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_else(|| file_name.to_owned());
+    format!("{}.pdf", stem)
+}
+
+/// Image data ready for embedding
+struct PdfImage {
+    width: u32,
+    height: u32,
+    color_space: &'static str,
+    /// Filter of the data
+    filter: &'static str,
+    data: Vec<u8>,
+    /// Adobe CMYK JPEGs are stored inverted
+    inverted: bool,
+}
+
+/// Size and components of a JPEG from its start of frame segment
+fn jpeg_frame(body: &[u8]) -> Option<(u32, u32, u8)> {
+    let mut pos = 2;
+    while pos + 4 <= body.len() {
+        if body[pos] != 0xff {
+            return None;
+        }
+        let marker = body[pos + 1];
+        let length = u16::from_be_bytes([body[pos + 2], body[pos + 3]]) as usize;
+        // SOF0 to SOF15 without DHT, JPG and DAC
+        if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+            let frame = body.get(pos + 4..pos + 10)?;
+            let height = u16::from_be_bytes([frame[1], frame[2]]) as u32;
+            let width = u16::from_be_bytes([frame[3], frame[4]]) as u32;
+            return Some((width, height, frame[5]));
+        }
+        pos += 2 + length;
+    }
+    None
+}
+
+fn jpeg_image(body: &[u8]) -> Result<PdfImage> {
+    let (width, height, components) = jpeg_frame(body).context("Invalid JPEG")?;
+    let color_space = match components {
+        1 => "DeviceGray",
+        3 => "DeviceRGB",
+        4 => "DeviceCMYK",
+        x => bail!("Unsupported JPEG with {} components", x),
+    };
+    Ok(PdfImage {
+        width,
+        height,
+        color_space,
+        filter: "DCTDecode",
+        data: body.to_vec(),
+        inverted: components == 4,
+    })
+}
+
+fn decoded_image(body: &[u8]) -> Result<PdfImage> {
+    let image = image::load_from_memory(body)
+        .context("Can't decode image")?
+        .to_rgb8();
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(image.as_raw())?;
+    Ok(PdfImage {
+        width: image.width(),
+        height: image.height(),
+        color_space: "DeviceRGB",
+        filter: "FlateDecode",
+        data: encoder.finish()?,
+        inverted: false,
+    })
+}
+
+/// Wraps the image into a PDF page of the size of A4 in its longer side
+pub fn image_to_pdf(mimetype: &str, body: &[u8]) -> Result<Vec<u8>> {
+    let image = if mimetype.eq_ignore_ascii_case("image/jpeg") {
+        jpeg_image(body)?
+    } else {
+        decoded_image(body)?
+    };
+    if image.width == 0 || image.height == 0 {
+        bail!("Image is empty");
+    }
+    let scale = PAGE_LENGTH / image.width.max(image.height) as f64;
+    let page_width = (image.width as f64 * scale).round() as i64;
+    let page_height = (image.height as f64 * scale).round() as i64;
+
+    let mut document = Document::with_version("1.5");
+    let pages_id = document.new_object_id();
+    let mut image_dict = dictionary! {
+        "Type" => "XObject",
+        "Subtype" => "Image",
+        "Width" => image.width as i64,
+        "Height" => image.height as i64,
+        "ColorSpace" => image.color_space,
+        "BitsPerComponent" => 8_i64,
+        "Filter" => image.filter,
+    };
+    if image.inverted {
+        let decode: Vec<Object> = [1_i64, 0, 1, 0, 1, 0, 1, 0]
+            .iter()
+            .map(|x| Object::Integer(*x))
+            .collect();
+        image_dict.set("Decode", decode);
+    }
+    let image_id = document.add_object(Stream::new(image_dict, image.data));
+    let content = format!("q {} 0 0 {} 0 0 cm /Im0 Do Q", page_width, page_height);
+    let content_id = document.add_object(Stream::new(dictionary! {}, content.into_bytes()));
+    let media_box: Vec<Object> = vec![0, 0, page_width, page_height]
+        .into_iter()
+        .map(Object::Integer)
+        .collect();
+    let page_id = document.add_object(dictionary! {
+        "Type" => "Page",
+        "Parent" => pages_id,
+        "MediaBox" => media_box,
+        "Contents" => content_id,
+        "Resources" => dictionary! {
+            "XObject" => dictionary! { "Im0" => image_id },
+        },
+    });
+    document.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => vec![page_id.into()],
+            "Count" => 1_i64,
+        }),
+    );
+    let catalog_id = document.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+    document.trailer.set("Root", catalog_id);
+    let mut rv = Vec::new();
+    document.save_to(&mut rv).context("Can't write PDF")?;
+    Ok(rv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_to_pdf() {
+        let image = image::RgbImage::from_pixel(40, 20, image::Rgb([255, 0, 0]));
+        for (mimetype, format) in [
+            ("image/png", image::ImageOutputFormat::Png),
+            ("image/jpeg", image::ImageOutputFormat::Jpeg(80)),
+        ] {
+            let mut body = std::io::Cursor::new(Vec::new());
+            image.write_to(&mut body, format).unwrap();
+            let pdf = image_to_pdf(mimetype, body.get_ref()).unwrap();
+            assert!(pdf.starts_with(b"%PDF-1.5"));
+            let document = Document::load_mem(&pdf).unwrap();
+            assert_eq!(document.get_pages().len(), 1);
+        }
+        let frame = b"\xff\xd8\xff\xc0\x00\x11\x08\x00\x14\x00\x28\x03";
+        assert_eq!(jpeg_frame(frame), Some((40, 20, 3)));
+        assert!(image_to_pdf("image/png", b"not an image").is_err());
+        assert_eq!(pdf_file_name("receipt.JPG"), "receipt.pdf");
+    }
+}
@@ -0,0 +1,165 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Dead-letter directory of messages that failed.
+//!
+//! With `--dead-letter-dir` every message whose processing failed is
+//! written to the directory as `<sha256>.eml`, next to a `<sha256>.json`
+//! report with the time and the result of the failed run. A message that
+//! fails again replaces its entry, so redeliveries of the MTA don't pile
+//! up. Messages cancelled on shutdown are not written, their source
+//! delivers them again.
+//!
+//! The `retry` subcommand processes all entries again and removes the
+//! ones that succeed.
+
+use crate::{ErrorCategory, ProcessResult};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+const MAIL_EXTENSION: &str = "eml";
+const REPORT_EXTENSION: &str = "json";
+
+/// Failure report written next to the message
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    /// RFC 3339 time of the failure
+    time: String,
+    result: &'a ProcessResult,
+}
+
+/// Message of the dead-letter directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    /// Raw message
+    pub mail: PathBuf,
+    /// Failure report of the last run
+    pub report: PathBuf,
+}
+
+impl DeadLetter {
+    /// Removes the message and its report
+    pub fn remove(&self) -> Result<()> {
+        std::fs::remove_file(&self.mail)
+            .with_context(|| format!("Can't remove {}", self.mail.display()))?;
+        match std::fs::remove_file(&self.report) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("Can't remove {}", self.report.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Directory the failed messages are written to
+#[derive(Debug, Clone)]
+pub struct DeadLetterDir {
+    path: PathBuf,
+}
+
+impl DeadLetterDir {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Creates the directory if missing
+    pub fn create(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.path)
+            .with_context(|| format!("Can't create dead-letter dir {}", self.path.display()))
+    }
+
+    /// Returns true if the result is written to the directory, failed and
+    /// not cancelled
+    pub fn accepts(result: &ProcessResult) -> bool {
+        !result.is_success() && result.error_count(ErrorCategory::Cancelled) == 0
+    }
+
+    /// Writes the message and its failure report. The files are written
+    /// to temporary names and renamed, so `retry` never reads a partial
+    /// message.
+    pub fn store(&self, content: &[u8], result: &ProcessResult) -> Result<DeadLetter> {
+        self.create()?;
+        let name = format!("{:x}", Sha256::digest(content));
+        let entry = DeadLetter {
+            mail: self.path.join(&name).with_extension(MAIL_EXTENSION),
+            report: self.path.join(&name).with_extension(REPORT_EXTENSION),
+        };
+        let report = Report {
+            time: chrono::Local::now().to_rfc3339(),
+            result,
+        };
+        write_file(&entry.report, &serde_json::to_vec_pretty(&report)?)?;
+        write_file(&entry.mail, content)?;
+        Ok(entry)
+    }
+
+    /// Returns the messages of the directory, oldest first
+    pub fn entries(&self) -> Result<Vec<DeadLetter>> {
+        let read_dir = match std::fs::read_dir(&self.path) {
+            Ok(x) => x,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Can't read dead-letter dir {}", self.path.display()))
+            }
+        };
+        let mut entries = Vec::new();
+        for dir_entry in read_dir {
+            let dir_entry = dir_entry?;
+            let mail = dir_entry.path();
+            if mail.extension().and_then(|x| x.to_str()) != Some(MAIL_EXTENSION) {
+                continue;
+            }
+            let modified = dir_entry.metadata()?.modified()?;
+            let report = mail.with_extension(REPORT_EXTENSION);
+            entries.push((modified, DeadLetter { mail, report }));
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.mail.cmp(&b.1.mail)));
+        Ok(entries.into_iter().map(|x| x.1).collect())
+    }
+}
+
+/// Writes the file under a temporary name and renames it
+fn write_file(path: &Path, content: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, content).with_context(|| format!("Can't write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Can't write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dead_letter_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let dead_letters = DeadLetterDir::new(dir.path().join("failed"));
+        assert!(dead_letters.entries().unwrap().is_empty());
+
+        let mut result = ProcessResult::default();
+        assert!(!DeadLetterDir::accepts(&result));
+        result.add_error(ErrorCategory::Storage, "connection refused");
+        assert!(DeadLetterDir::accepts(&result));
+
+        let entry = dead_letters.store(b"Subject: a\r\n\r\n", &result).unwrap();
+        // failing again replaces the entry
+        dead_letters.store(b"Subject: a\r\n\r\n", &result).unwrap();
+        assert_eq!(dead_letters.entries().unwrap(), vec![entry.clone()]);
+        assert_eq!(std::fs::read(&entry.mail).unwrap(), b"Subject: a\r\n\r\n");
+        let report: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&entry.report).unwrap()).unwrap();
+        assert_eq!(report["result"]["errors"][0]["category"], "storage");
+
+        entry.remove().unwrap();
+        assert!(dead_letters.entries().unwrap().is_empty());
+
+        result.add_error(ErrorCategory::Cancelled, "shutdown");
+        assert!(!DeadLetterDir::accepts(&result));
+    }
+}
@@ -0,0 +1,169 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Detection of attachments stored before.
+//!
+//! Re-delivered mails would store their attachments again. With
+//! `--on-duplicate` the SHA-256 of every attachment is looked up in an
+//! index before the upload:
+//!
+//! * the state database, if `--state-db` is set, see [`crate::state`]
+//! * otherwise a marker object named by the hash in the first attachment
+//!   sink, below `--dedupe-prefix`, which is written after the upload
+//!
+//! Attachments repeated within one mail are duplicates too.
+//!
+//! Different attachments rendered to the same path are handled by
+//! `--on-conflict`, which looks up the path before the upload.
+
+use crate::pipeline::AttachmentSink;
+use crate::state::StateDb;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+pub const DEFAULT_DEDUPE_PREFIX: &str = ".invoice2storage/sha256";
+
+/// Length of the hash suffix of renamed files
+const SUFFIX_LENGTH: usize = 8;
+
+/// Numbered paths tried before giving up
+pub const MAX_NUMBERED_PATHS: usize = 1000;
+
+/// What happens to an attachment stored before
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OnDuplicate {
+    /// Not stored again
+    Skip,
+    /// Stored at its rendered path, replacing a file of the same name
+    Overwrite,
+    /// Stored with the start of the hash appended to the file name
+    Rename,
+}
+
+/// What happens to an attachment rendered to the path of a stored file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    /// The stored file is replaced
+    #[default]
+    Overwrite,
+    /// `-1`, `-2` and so on is appended to the file name
+    Number,
+    /// The start of the content hash is appended to the file name
+    Hash,
+}
+
+/// Known hashes of the stored attachments
+pub enum DedupeIndex {
+    /// Hashes read from the state database
+    State(HashSet<String>),
+    /// Marker objects in the sink
+    Markers {
+        sink: Arc<dyn AttachmentSink>,
+        prefix: String,
+    },
+}
+
+impl DedupeIndex {
+    /// Reads the hashes of the state database, a missing database is empty
+    pub fn from_state(state: &StateDb) -> Result<Self> {
+        Ok(DedupeIndex::State(state.hashes()?))
+    }
+
+    pub fn markers(sink: Arc<dyn AttachmentSink>, prefix: &str) -> Self {
+        DedupeIndex::Markers {
+            sink,
+            prefix: prefix.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    /// Returns true if the content was stored before
+    pub async fn contains(&self, sha256: &str) -> Result<bool> {
+        match self {
+            DedupeIndex::State(hashes) => Ok(hashes.contains(sha256)),
+            DedupeIndex::Markers { sink, prefix } => {
+                sink.exists(&marker_path(prefix, sha256)).await
+            }
+        }
+    }
+
+    /// Path of the marker to write after the upload, if markers are used
+    pub fn marker(&self, sha256: &str) -> Option<String> {
+        match self {
+            DedupeIndex::State(_) => None,
+            DedupeIndex::Markers { prefix, .. } => Some(marker_path(prefix, sha256)),
+        }
+    }
+}
+
+fn marker_path(prefix: &str, sha256: &str) -> String {
+    if prefix.is_empty() {
+        sha256.to_owned()
+    } else {
+        format!("{}/{}", prefix, sha256)
+    }
+}
+
+/// Appends the suffix to the file name of the path, before the extension
+pub fn with_suffix(path: &str, suffix: &str) -> String {
+    let (folder, file_name) = match path.rsplit_once('/') {
+        Some((folder, file_name)) => (Some(folder), file_name),
+        None => (None, path),
+    };
+    let file_name = match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{}-{}.{}", stem, suffix, extension)
+        }
+        _ => format!("{}-{}", file_name, suffix),
+    };
+    match folder {
+        Some(folder) => format!("{}/{}", folder, file_name),
+        None => file_name,
+    }
+}
+
+/// Path of a renamed duplicate
+pub fn renamed_path(path: &str, sha256: &str) -> String {
+    with_suffix(path, &sha256[..sha256.len().min(SUFFIX_LENGTH)])
+}
+
+/// Alternative paths for a taken path, in the order they are tried
+pub fn conflict_paths(path: &str, sha256: &str, on_conflict: OnConflict) -> Vec<String> {
+    match on_conflict {
+        OnConflict::Overwrite => Vec::new(),
+        OnConflict::Number => (1..=MAX_NUMBERED_PATHS)
+            .map(|x| with_suffix(path, &x.to_string()))
+            .collect(),
+        OnConflict::Hash => vec![renamed_path(path, sha256)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renamed_path() {
+        let sha256 = "0123456789abcdef";
+        assert_eq!(
+            renamed_path("alice/invoice.pdf", sha256),
+            "alice/invoice-01234567.pdf"
+        );
+        assert_eq!(renamed_path("a.b/invoice", sha256), "a.b/invoice-01234567");
+        assert_eq!(renamed_path(".hidden", sha256), ".hidden-01234567");
+        assert_eq!(with_suffix("2023/scan.tar.gz", "1"), "2023/scan.tar-1.gz");
+        let numbered = conflict_paths("a/x.pdf", sha256, OnConflict::Number);
+        assert_eq!(
+            numbered[..2],
+            ["a/x-1.pdf".to_owned(), "a/x-2.pdf".to_owned()]
+        );
+        assert_eq!(
+            conflict_paths("x.pdf", sha256, OnConflict::Hash),
+            vec!["x-01234567.pdf"]
+        );
+        assert!(conflict_paths("x.pdf", sha256, OnConflict::Overwrite).is_empty());
+    }
+}
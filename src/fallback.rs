@@ -0,0 +1,316 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Fallback backends, the spool and the manifest of the files they hold.
+//!
+//! If a backend fails after all retries, for example the WebDAV server is
+//! down, the attachment is stored in the first fallback backend that
+//! accepts it instead of failing the message:
+//!
+//! ```toml
+//! http_path = "https://dav.example.com/invoices"
+//! fallback_path = ["/var/spool/invoice2storage"]
+//! fallback_manifest = "/var/lib/invoice2storage/fallback.jsonl"
+//! ```
+//!
+//! Pipelines declare them as `[[pipeline.fallback_sinks]]` like the
+//! attachment sinks. Every file a fallback holds is appended to the
+//! manifest with the backends it should have been stored in.
+//!
+//! With `--spool-dir` the attachments are only written to the local spool,
+//! so a MDA delivery succeeds while the storage server is offline, and all
+//! backends are recorded in the manifest.
+//!
+//! `invoice2storage sync` moves the files of the manifest to their
+//! backends and deletes them from the spool or fallback. Files that can't
+//! be stored in all their backends stay for the next run.
+
+use crate::pipeline::AttachmentSink;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Manifest of `--spool-dir` without `--fallback-manifest`, hidden in the
+/// spool
+pub const SPOOL_MANIFEST: &str = ".invoice2storage-manifest.jsonl";
+
+/// One file held by a fallback backend
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ManifestEntry {
+    /// Unix timestamp
+    pub time: u64,
+    /// Rendered output path
+    pub path: String,
+    /// Name of the backend holding the file
+    pub backend: String,
+    /// Names of the failed backends the file belongs in
+    pub targets: Vec<String>,
+}
+
+impl ManifestEntry {
+    pub fn new(path: &str, backend: String, targets: Vec<String>) -> Self {
+        Self {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or_default(),
+            path: path.to_owned(),
+            backend,
+            targets,
+        }
+    }
+}
+
+/// JSON lines file of the entries. Appends are serialized with a file
+/// lock, so several processes can share the manifest.
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    path: PathBuf,
+}
+
+impl Manifest {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn append(&self, entry: &ManifestEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .with_context(|| format!("Can't open fallback manifest {}", self.path.display()))?;
+        crate::audit::lock(&file)?;
+        file.write_all(&line)
+            .with_context(|| format!("Can't write fallback manifest {}", self.path.display()))?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// Reads all entries, empty before the first file is recorded. Damaged
+    /// lines, like a partial write, are skipped.
+    pub fn entries(&self) -> Result<Vec<ManifestEntry>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let content = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("Can't read fallback manifest {}", self.path.display()))?;
+        let mut rv = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(line) {
+                Ok(entry) => rv.push(entry),
+                Err(err) => log::warn!(
+                    "{}:{}: skipping entry: {}",
+                    self.path.display(),
+                    number + 1,
+                    err
+                ),
+            }
+        }
+        Ok(rv)
+    }
+
+    /// Replaces the entries with the new ones or removes them if there is
+    /// none. Entries appended meanwhile and damaged lines are kept.
+    pub fn update(&self, changes: &[(ManifestEntry, Option<ManifestEntry>)]) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)
+            .with_context(|| format!("Can't open fallback manifest {}", self.path.display()))?;
+        crate::audit::lock(&file)?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        let mut lines = Vec::new();
+        for line in content.lines().filter(|x| !x.trim().is_empty()) {
+            let change = serde_json::from_str::<ManifestEntry>(line)
+                .ok()
+                .and_then(|entry| changes.iter().find(|(old, _)| old == &entry));
+            match change {
+                Some((_, Some(new))) => serde_json::to_writer(&mut lines, new)?,
+                Some((_, None)) => continue,
+                None => lines.extend_from_slice(line.as_bytes()),
+            }
+            lines.push(b'\n');
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&lines)
+            .with_context(|| format!("Can't write fallback manifest {}", self.path.display()))?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Result of a `sync` run
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    /// Paths of the files stored in all their backends
+    pub moved: Vec<String>,
+    /// Paths of the files left with the errors
+    pub failed: Vec<(String, String)>,
+}
+
+/// Moves the files of the manifest from the sink holding them to the sinks
+/// they belong in. The manifest keeps the backends that failed.
+pub async fn sync(
+    manifest: &Manifest,
+    holders: &[Arc<dyn AttachmentSink>],
+    sinks: &[Arc<dyn AttachmentSink>],
+) -> Result<SyncSummary> {
+    let entries = manifest.entries()?;
+    let mut summary = SyncSummary::default();
+    let mut changes = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let same_file = |x: &&ManifestEntry| x.backend == entry.backend && x.path == entry.path;
+        // the file was stored again, the last entry moves it
+        if entries[index + 1..].iter().any(|x| same_file(&x)) {
+            changes.push((entry.clone(), None));
+            continue;
+        }
+        let mut targets = Vec::new();
+        for earlier in entries[..index].iter().filter(same_file) {
+            targets.extend(earlier.targets.iter().cloned());
+        }
+        targets.extend(entry.targets.iter().cloned());
+        targets.sort();
+        targets.dedup();
+        let merged = ManifestEntry {
+            targets,
+            ..entry.clone()
+        };
+        let body = match holders.iter().find(|x| x.name() == entry.backend) {
+            Some(holder) => holder.get(&entry.path).await.map(|x| (holder, x)),
+            None => Err(anyhow!("Unknown backend {}", entry.backend)),
+        };
+        let (holder, body) = match body {
+            Ok(x) => x,
+            Err(err) => {
+                summary
+                    .failed
+                    .push((entry.path.clone(), format!("{:#}", err)));
+                if &merged != entry {
+                    changes.push((entry.clone(), Some(merged)));
+                }
+                continue;
+            }
+        };
+        let mut remaining = Vec::new();
+        let mut errors = Vec::new();
+        for target in merged.targets.iter().cloned() {
+            let result = match sinks.iter().find(|x| x.name() == target) {
+                Some(sink) => sink.put(&entry.path, body.clone()).await,
+                None => Err(anyhow!("Unknown backend")),
+            };
+            if let Err(err) = result {
+                errors.push(format!("{}: {:#}", target, err));
+                remaining.push(target);
+            }
+        }
+        if remaining.is_empty() {
+            log::info!("Moved {} from {}", entry.path, entry.backend);
+            if let Err(err) = holder.delete(&entry.path).await {
+                log::warn!(
+                    "Can't delete {} in {}: {:#}",
+                    entry.path,
+                    entry.backend,
+                    err
+                );
+            }
+            summary.moved.push(entry.path.clone());
+            changes.push((entry.clone(), None));
+        } else {
+            summary.failed.push((entry.path.clone(), errors.join(", ")));
+            let left = ManifestEntry {
+                targets: remaining,
+                ..merged
+            };
+            changes.push((entry.clone(), Some(left)));
+        }
+    }
+    if !changes.is_empty() {
+        manifest.update(&changes)?;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = Manifest::new(dir.path().join("fallback.jsonl"));
+        assert!(manifest.entries().unwrap().is_empty());
+
+        let entry = ManifestEntry::new(
+            "alice/invoice.pdf",
+            "local:/var/spool".to_owned(),
+            vec!["http:dav.example.com".to_owned()],
+        );
+        manifest.append(&entry).unwrap();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("fallback.jsonl"))
+            .unwrap()
+            .write_all(b"{\"path\":\n")
+            .unwrap();
+        assert_eq!(manifest.entries().unwrap(), vec![entry.clone()]);
+
+        let moved = ManifestEntry::new("bob/invoice.pdf", entry.backend.clone(), Vec::new());
+        manifest.append(&moved).unwrap();
+        let left = ManifestEntry {
+            targets: vec!["s3:archive".to_owned()],
+            ..entry.clone()
+        };
+        manifest
+            .update(&[(entry, Some(left.clone())), (moved, None)])
+            .unwrap();
+        assert_eq!(manifest.entries().unwrap(), vec![left]);
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let sink = |name: &str| -> Arc<dyn AttachmentSink> {
+            Arc::new(crate::sinks::ObjectStoreSink::new(
+                name,
+                Arc::new(object_store::memory::InMemory::new()),
+            ))
+        };
+        let spool = sink("spool");
+        let archive = sink("archive");
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = Manifest::new(dir.path().join("fallback.jsonl"));
+        for path in ["alice/a.pdf", "alice/b.pdf"] {
+            spool
+                .put(path, bytes::Bytes::from_static(b"%PDF"))
+                .await
+                .unwrap();
+        }
+        let entry = |path: &str, target: &str| {
+            ManifestEntry::new(path, "spool".to_owned(), vec![target.to_owned()])
+        };
+        manifest.append(&entry("alice/a.pdf", "archive")).unwrap();
+        let unknown = entry("alice/b.pdf", "gone");
+        manifest.append(&unknown).unwrap();
+
+        let summary = sync(&manifest, &[spool.clone()], &[archive.clone()])
+            .await
+            .unwrap();
+        assert_eq!(summary.moved, vec!["alice/a.pdf".to_owned()]);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(manifest.entries().unwrap(), vec![unknown]);
+        assert_eq!(archive.get("alice/a.pdf").await.unwrap(), b"%PDF"[..]);
+        assert!(!spool.exists("alice/a.pdf").await.unwrap());
+        assert!(spool.exists("alice/b.pdf").await.unwrap());
+    }
+}
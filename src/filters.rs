@@ -0,0 +1,130 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Template filters for object keys.
+//!
+//! Registered next to [`crate::escape_filename`], they keep the rendered
+//! paths URL-safe and within the key length of the storage backends:
+//!
+//! * `sha256`: hex encoded SHA-256 of the value
+//! * `slugify`: lowercase ASCII letters and digits separated by dashes
+//! * `truncate_bytes(n)`: at most `n` bytes, keeping the file extension
+//!   unless `keep_extension=false`
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tera::Value;
+
+/// Extensions longer than this are truncated like the rest of the name
+const MAX_EXTENSION_LENGTH: usize = 10;
+
+/// Hex encoded SHA-256 of the value
+pub fn sha256(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let s = tera::try_get_value!("sha256", "value", String, value);
+    Ok(Value::String(format!("{:x}", Sha256::digest(s.as_bytes()))))
+}
+
+/// Lowercase ASCII slug of the value, German umlauts are transliterated and
+/// all other characters replaced by single dashes
+pub fn slugify(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let s = tera::try_get_value!("slugify", "value", String, value);
+    let mut output = String::with_capacity(s.len());
+    let mut dash = false;
+    for c in s.chars().flat_map(char::to_lowercase) {
+        let replacement = match c {
+            'a'..='z' | '0'..='9' => None,
+            'ä' => Some("ae"),
+            'ö' => Some("oe"),
+            'ü' => Some("ue"),
+            'ß' => Some("ss"),
+            _ => {
+                dash = !output.is_empty();
+                continue;
+            }
+        };
+        if dash {
+            output.push('-');
+            dash = false;
+        }
+        match replacement {
+            Some(x) => output.push_str(x),
+            None => output.push(c),
+        }
+    }
+    Ok(Value::String(output))
+}
+
+/// Shortens the value to at most `n` bytes without splitting characters
+pub fn truncate_bytes(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let s = tera::try_get_value!("truncate_bytes", "value", String, value);
+    let length = match args.get("n") {
+        Some(n) => tera::try_get_value!("truncate_bytes", "n", usize, n),
+        None => {
+            return Err(tera::Error::msg(
+                "Filter `truncate_bytes` expects an argument `n`",
+            ))
+        }
+    };
+    let keep_extension = match args.get("keep_extension") {
+        Some(x) => tera::try_get_value!("truncate_bytes", "keep_extension", bool, x),
+        None => true,
+    };
+    if s.len() <= length {
+        return Ok(Value::String(s));
+    }
+    let extension = match s.rfind('.') {
+        Some(dot) if keep_extension && dot > 0 && s.len() - dot <= MAX_EXTENSION_LENGTH => {
+            &s[dot..]
+        }
+        _ => "",
+    };
+    let stem = &s[..s.len() - extension.len()];
+    let mut end = length.saturating_sub(extension.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    Ok(Value::String(format!("{}{}", &stem[..end], extension)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::create_template_engine;
+
+    #[test]
+    fn test_filters() {
+        let mut tt = create_template_engine();
+        let mut context = tera::Context::new();
+        context.insert("seller", "Müller & Söhne GmbH, Straße 1");
+        context.insert("file_name", "Rechnung März 2023.pdf");
+        let render =
+            |tt: &mut tera::Tera, template: &str| tt.render_str(template, &context).unwrap();
+        assert_eq!(
+            render(&mut tt, "{{ seller | slugify }}"),
+            "mueller-soehne-gmbh-strasse-1"
+        );
+        assert_eq!(render(&mut tt, "{{ '--A b--' | slugify }}"), "a-b");
+        assert_eq!(
+            render(&mut tt, "{{ 'abc' | sha256 }}"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // the ä is not split
+        assert_eq!(
+            render(&mut tt, "{{ file_name | truncate_bytes(n=15) }}"),
+            "Rechnung M.pdf"
+        );
+        assert_eq!(
+            render(
+                &mut tt,
+                "{{ file_name | truncate_bytes(n=10, keep_extension=false) }}"
+            ),
+            "Rechnung M"
+        );
+        assert_eq!(
+            render(&mut tt, "{{ 'a.pdf' | truncate_bytes(n=10) }}"),
+            "a.pdf"
+        );
+        assert!(tt
+            .render_str("{{ 'a' | truncate_bytes }}", &context)
+            .is_err());
+    }
+}
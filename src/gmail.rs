@@ -0,0 +1,394 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Gmail API source with labels instead of IMAP flags.
+//!
+//! `--gmail-query` processes the messages matching a Gmail search, like
+//! `has:attachment label:invoices`, with the Google login of `auth login`:
+//!
+//! ```toml
+//! oauth_provider = "google"
+//! oauth_client_id = "….apps.googleusercontent.com"
+//! gmail_query = "has:attachment label:invoices"
+//! ```
+//!
+//! The flags of the result are mapped onto labels: `\Flagged` stars the
+//! message, `\Seen` removes `UNREAD` and other flags are added as user
+//! labels, created if missing. The rendered `mail_template` is added as a
+//! label too, its `.` separated levels become nested labels. Processed
+//! messages get `gmail_label` and are left out of the next searches,
+//! messages that couldn't be stored are fetched again.
+
+use crate::oauth::OAuthClient;
+use crate::pipeline::{Message, Source};
+use crate::{CancellationToken, ErrorCategory, ProcessResult};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine;
+use bytes::Bytes;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+const GMAIL_URL: &str = "https://gmail.googleapis.com/gmail/v1/users/me/";
+
+/// Label of the processed messages
+pub const DEFAULT_GMAIL_LABEL: &str = "invoice2storage";
+
+/// Labels of the system, their id is the name
+const SYSTEM_LABELS: &[&str] = &["IMPORTANT", "INBOX", "SPAM", "STARRED", "TRASH", "UNREAD"];
+
+/// Messages requested per page
+const PAGE_SIZE: usize = 100;
+
+/// The raw message is base64url, padded or not
+const RAW_BASE64: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MessageList {
+    #[serde(default)]
+    messages: Vec<MessageRef>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageRef {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawMessage {
+    raw: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LabelList {
+    #[serde(default)]
+    labels: Vec<Label>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Label {
+    id: String,
+    name: String,
+}
+
+/// Names of the labels added and removed for the result flags and the
+/// rendered mail target
+fn label_changes(flags: &[String], target: Option<&str>) -> (Vec<String>, Vec<String>) {
+    let mut add = Vec::new();
+    let mut remove = Vec::new();
+    for flag in flags {
+        match flag.to_lowercase().as_str() {
+            "\\flagged" | "\\flag" => add.push("STARRED".to_owned()),
+            "\\seen" => remove.push("UNREAD".to_owned()),
+            other if other.starts_with('\\') => {
+                log::debug!("IMAP flag {} has no Gmail label", flag)
+            }
+            "" => (),
+            _ => add.push(flag.clone()),
+        }
+    }
+    if let Some(target) = target.filter(|x| !x.is_empty()) {
+        add.push(target.replace('.', "/"));
+    }
+    add.dedup();
+    (add, remove)
+}
+
+/// Search of the query without the processed messages
+fn search_query(query: &str, label: &str) -> String {
+    format!("({}) -label:{}", query, label.replace(' ', "-"))
+}
+
+/// Fetches the messages matching a Gmail search
+pub struct GmailSource {
+    query: String,
+    label: String,
+    oauth: Arc<OAuthClient>,
+    http: reqwest::Client,
+    interval: Option<Duration>,
+    cancel: CancellationToken,
+    /// Ids of the user labels by name
+    label_ids: HashMap<String, String>,
+    pending: VecDeque<String>,
+    /// Message returned by the last `next_message`
+    current: Option<String>,
+    searched: bool,
+}
+
+impl std::fmt::Debug for GmailSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GmailSource")
+            .field("query", &self.query)
+            .field("label", &self.label)
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GmailSource {
+    pub fn new(query: String, oauth: Arc<OAuthClient>, http: reqwest::Client) -> Self {
+        Self {
+            query,
+            label: DEFAULT_GMAIL_LABEL.to_owned(),
+            oauth,
+            http,
+            interval: None,
+            cancel: CancellationToken::new(),
+            label_ids: HashMap::new(),
+            pending: VecDeque::new(),
+            current: None,
+            searched: false,
+        }
+    }
+
+    /// Label of the processed messages
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = label.into();
+        self
+    }
+
+    /// Searches again after the interval instead of ending after the
+    /// first pass
+    pub fn with_interval(mut self, interval: Option<Duration>) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    fn url(segments: &[&str]) -> Result<Url> {
+        let mut url = Url::parse(GMAIL_URL)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("Invalid Gmail url"))?
+            .pop_if_empty()
+            .extend(segments);
+        Ok(url)
+    }
+
+    /// Sends the authorized request and fails on error responses with
+    /// the message of the API
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        url: Url,
+        body: Option<serde_json::Value>,
+    ) -> Result<Bytes> {
+        let token = self.oauth.access_token().await?;
+        let mut request = self.http.request(method, url).bearer_auth(token);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        if !status.is_success() {
+            bail!(
+                "Gmail API request failed: {} {}",
+                status,
+                String::from_utf8_lossy(&body)
+            );
+        }
+        Ok(body)
+    }
+
+    /// Id of the label, user labels are created if missing
+    async fn label_id(&mut self, name: &str) -> Result<String> {
+        if SYSTEM_LABELS.contains(&name) {
+            return Ok(name.to_owned());
+        }
+        if self.label_ids.is_empty() {
+            let body = self
+                .send(reqwest::Method::GET, Self::url(&["labels"])?, None)
+                .await?;
+            let list: LabelList = serde_json::from_slice(&body).context("Invalid label list")?;
+            self.label_ids = list.labels.into_iter().map(|x| (x.name, x.id)).collect();
+        }
+        if let Some(id) = self.label_ids.get(name) {
+            return Ok(id.clone());
+        }
+        log::info!("Creating Gmail label: {}", name);
+        let body = self
+            .send(
+                reqwest::Method::POST,
+                Self::url(&["labels"])?,
+                Some(serde_json::json!({ "name": name })),
+            )
+            .await?;
+        let label: Label = serde_json::from_slice(&body).context("Invalid label")?;
+        self.label_ids.insert(label.name, label.id.clone());
+        Ok(label.id)
+    }
+
+    /// Queues the matching messages that weren't processed before
+    async fn search(&mut self) -> Result<()> {
+        let query = search_query(&self.query, &self.label);
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut url = Self::url(&["messages"])?;
+            url.query_pairs_mut()
+                .append_pair("q", &query)
+                .append_pair("maxResults", &PAGE_SIZE.to_string());
+            if let Some(token) = &page_token {
+                url.query_pairs_mut().append_pair("pageToken", token);
+            }
+            let body = self.send(reqwest::Method::GET, url, None).await?;
+            let list: MessageList =
+                serde_json::from_slice(&body).context("Invalid message list")?;
+            self.pending.extend(list.messages.into_iter().map(|x| x.id));
+            page_token = list.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+        log::debug!("{} messages match {}", self.pending.len(), query);
+        Ok(())
+    }
+
+    /// Downloads the raw message
+    async fn fetch(&self, id: &str) -> Result<Bytes> {
+        let mut url = Self::url(&["messages", id])?;
+        url.query_pairs_mut().append_pair("format", "raw");
+        let body = self.send(reqwest::Method::GET, url, None).await?;
+        let message: RawMessage = serde_json::from_slice(&body).context("Invalid message")?;
+        let content = RAW_BASE64
+            .decode(message.raw.trim())
+            .context("Invalid raw message")?;
+        Ok(Bytes::from(content))
+    }
+
+    /// Adds the processed label and the labels of the flags and target
+    async fn label(&mut self, id: &str, flags: &[String], target: Option<&str>) -> Result<()> {
+        let (add, remove) = label_changes(flags, target);
+        let processed = self.label.clone();
+        let mut add_ids = vec![self.label_id(&processed).await?];
+        for name in add {
+            add_ids.push(self.label_id(&name).await?);
+        }
+        let mut remove_ids = Vec::new();
+        for name in remove {
+            remove_ids.push(self.label_id(&name).await?);
+        }
+        let body = serde_json::json!({
+            "addLabelIds": add_ids,
+            "removeLabelIds": remove_ids,
+        });
+        self.send(
+            reqwest::Method::POST,
+            Self::url(&["messages", id, "modify"])?,
+            Some(body),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Drops the queued messages after a failure while polling
+    fn reset(&mut self, err: anyhow::Error) {
+        log::error!("Can't fetch Gmail messages: {:#}", err);
+        self.pending.clear();
+    }
+}
+
+#[async_trait]
+impl Source for GmailSource {
+    async fn next_message(&mut self) -> Result<Option<Message>> {
+        loop {
+            if let Some(id) = self.pending.pop_front() {
+                let content = match self.fetch(&id).await {
+                    Ok(x) => x,
+                    Err(err) if self.interval.is_some() => {
+                        self.reset(err);
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+                self.current = Some(id.clone());
+                return Ok(Some(Message {
+                    id: format!("gmail:{}", id),
+                    content,
+                    mailbox: None,
+                }));
+            }
+            if self.searched {
+                let interval = match self.interval {
+                    Some(x) => x,
+                    None => return Ok(None),
+                };
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => (),
+                    _ = self.cancel.cancelled() => return Ok(None),
+                }
+            }
+            self.searched = true;
+            if let Err(err) = self.search().await {
+                if self.interval.is_none() {
+                    return Err(err);
+                }
+                self.reset(err);
+            }
+        }
+    }
+
+    async fn finish(&mut self, message: &Message, result: &ProcessResult) -> Result<()> {
+        let id = match self.current.take() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let retry = result.errors.iter().any(|x| {
+            matches!(
+                x.category,
+                ErrorCategory::MailStore | ErrorCategory::Cancelled
+            )
+        });
+        if retry {
+            log::warn!("{} is fetched again", message.id);
+            return Ok(());
+        }
+        self.label(&id, &result.flags, result.mailbox.as_deref())
+            .await
+            .with_context(|| format!("Can't label {}", message.id))
+    }
+
+    fn set_cancellation(&mut self, cancel: CancellationToken) {
+        self.cancel = cancel;
+    }
+
+    async fn check(&mut self) -> Result<()> {
+        self.oauth
+            .access_token()
+            .await
+            .context("Can't fetch the Gmail access token")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label_changes() {
+        let flags = vec![
+            "\\Flagged".to_owned(),
+            "\\Seen".to_owned(),
+            "\\Answered".to_owned(),
+            "invoice".to_owned(),
+        ];
+        let (add, remove) = label_changes(&flags, Some("alice.done"));
+        assert_eq!(add, vec!["STARRED", "invoice", "alice/done"]);
+        assert_eq!(remove, vec!["UNREAD"]);
+        assert_eq!(label_changes(&[], Some("")), (Vec::new(), Vec::new()));
+
+        assert_eq!(
+            search_query("has:attachment label:invoices", "invoice2storage done"),
+            "(has:attachment label:invoices) -label:invoice2storage-done"
+        );
+        assert_eq!(RAW_BASE64.decode("U3ViamVjdDogYQ").unwrap(), b"Subject: a");
+    }
+}
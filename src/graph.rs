@@ -0,0 +1,412 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Microsoft Graph source for Office 365 mailboxes where IMAP is disabled
+//! by policy.
+//!
+//! An app registration with the `Mail.ReadWrite` application permission
+//! reads the unread messages of a user or shared mailbox with the client
+//! credentials of `oauth_client_id` and `oauth_client_secret`:
+//!
+//! ```toml
+//! graph_tenant = "contoso.onmicrosoft.com"
+//! graph_mailbox = "invoices@contoso.com"
+//! graph_folders = ["inbox"]
+//! oauth_client_id = "00000000-0000-0000-0000-000000000000"
+//! oauth_client_secret = "…"
+//! ```
+//!
+//! Like with the IMAP source, processed messages are moved to the folder of
+//! the rendered `mail_template`. Its levels are separated by `.` and created
+//! below the top of the mailbox if missing. `\Flagged` sets the follow-up
+//! flag, `\Seen` marks the message as read and other flags are added as
+//! categories. Messages that couldn't be stored stay unread and are fetched
+//! again by the next pass.
+
+use crate::oauth::ClientCredentials;
+use crate::pipeline::{Message, Source};
+use crate::{CancellationToken, ErrorCategory, ProcessResult};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use url::Url;
+
+const GRAPH_URL: &str = "https://graph.microsoft.com/v1.0/";
+
+/// Scope of the application permissions granted to the app
+pub const GRAPH_SCOPE: &str = "https://graph.microsoft.com/.default";
+
+pub const DEFAULT_GRAPH_FOLDER: &str = "inbox";
+
+/// Folders addressed by their name instead of an id
+const WELL_KNOWN_FOLDERS: &[&str] = &[
+    "archive",
+    "deleteditems",
+    "drafts",
+    "inbox",
+    "junkemail",
+    "msgfolderroot",
+    "outbox",
+    "sentitems",
+];
+
+/// Messages requested per page
+const PAGE_SIZE: usize = 50;
+
+#[derive(Debug, Deserialize)]
+struct Page<T> {
+    value: Vec<T>,
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageRef {
+    id: String,
+    #[serde(default)]
+    categories: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Folder {
+    id: String,
+}
+
+/// Fetched message waiting to be processed
+#[derive(Debug, Clone)]
+struct Pending {
+    folder: String,
+    id: String,
+    categories: Vec<String>,
+}
+
+/// Body of the PATCH request setting the result flags, `None` if nothing
+/// changes
+fn update_body(flags: &[String], categories: &[String]) -> Option<serde_json::Value> {
+    let mut body = serde_json::Map::new();
+    let existing = categories.len();
+    let mut categories = categories.to_vec();
+    for flag in flags {
+        match flag.to_lowercase().as_str() {
+            "\\flagged" => {
+                body.insert(
+                    "flag".to_owned(),
+                    serde_json::json!({ "flagStatus": "flagged" }),
+                );
+            }
+            "\\seen" => {
+                body.insert("isRead".to_owned(), serde_json::Value::Bool(true));
+            }
+            _ => {
+                let category = flag.trim_start_matches('\\').to_owned();
+                if !category.is_empty() && !categories.contains(&category) {
+                    categories.push(category);
+                }
+            }
+        }
+    }
+    if categories.len() > existing {
+        body.insert("categories".to_owned(), serde_json::json!(categories));
+    }
+    (!body.is_empty()).then(|| serde_json::Value::Object(body))
+}
+
+/// Quotes a string literal of an OData filter
+fn odata_string(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Fetches the unread messages of mailbox folders with the Graph API
+pub struct GraphSource {
+    mailbox: String,
+    folders: Vec<String>,
+    credentials: ClientCredentials,
+    http: reqwest::Client,
+    interval: Option<Duration>,
+    cancel: CancellationToken,
+    /// Ids of the resolved folders by path
+    folder_ids: HashMap<String, String>,
+    pending: VecDeque<Pending>,
+    /// Message returned by the last `next_message`
+    current: Option<Pending>,
+    searched: bool,
+}
+
+impl std::fmt::Debug for GraphSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GraphSource")
+            .field("mailbox", &self.mailbox)
+            .field("folders", &self.folders)
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GraphSource {
+    pub fn new(
+        mailbox: String,
+        folders: Vec<String>,
+        credentials: ClientCredentials,
+        http: reqwest::Client,
+    ) -> Self {
+        Self {
+            mailbox,
+            folders,
+            credentials,
+            http,
+            interval: None,
+            cancel: CancellationToken::new(),
+            folder_ids: HashMap::new(),
+            pending: VecDeque::new(),
+            current: None,
+            searched: false,
+        }
+    }
+
+    /// Searches the folders again after the interval instead of ending
+    /// after the first pass
+    pub fn with_interval(mut self, interval: Option<Duration>) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Url below the mailbox of the user
+    fn url(&self, segments: &[&str]) -> Result<Url> {
+        let mut url = Url::parse(GRAPH_URL)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("Invalid Graph url"))?
+            .pop_if_empty()
+            .extend(["users", self.mailbox.as_str()])
+            .extend(segments);
+        Ok(url)
+    }
+
+    async fn request(&self, method: reqwest::Method, url: Url) -> Result<reqwest::RequestBuilder> {
+        let token = self.credentials.access_token().await?;
+        Ok(self.http.request(method, url).bearer_auth(token))
+    }
+
+    /// Sends the request and fails on error responses with the message of
+    /// the API
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<Bytes> {
+        let response = request.send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        if !status.is_success() {
+            bail!(
+                "Graph API request failed: {} {}",
+                status,
+                String::from_utf8_lossy(&body)
+            );
+        }
+        Ok(body)
+    }
+
+    /// Id of the folder of the `.` separated path below the top of the
+    /// mailbox, missing folders are created if `create` is set
+    async fn folder_id(&mut self, path: &str, create: bool) -> Result<String> {
+        if WELL_KNOWN_FOLDERS.contains(&path.to_lowercase().as_str()) {
+            return Ok(path.to_lowercase());
+        }
+        if let Some(id) = self.folder_ids.get(path) {
+            return Ok(id.clone());
+        }
+        let mut parent = "msgfolderroot".to_owned();
+        for name in path.split('.').filter(|x| !x.is_empty()) {
+            let mut url = self.url(&["mailFolders", &parent, "childFolders"])?;
+            url.query_pairs_mut()
+                .append_pair("$filter", &format!("displayName eq {}", odata_string(name)))
+                .append_pair("$select", "id");
+            let request = self.request(reqwest::Method::GET, url).await?;
+            let page: Page<Folder> = serde_json::from_slice(&self.send(request).await?)
+                .context("Invalid folder list")?;
+            parent = match page.value.into_iter().next() {
+                Some(folder) => folder.id,
+                None if create => {
+                    log::info!("Creating target folder: {}", path);
+                    let url = self.url(&["mailFolders", &parent, "childFolders"])?;
+                    let request = self
+                        .request(reqwest::Method::POST, url)
+                        .await?
+                        .json(&serde_json::json!({ "displayName": name }));
+                    let folder: Folder = serde_json::from_slice(&self.send(request).await?)
+                        .context("Invalid folder")?;
+                    folder.id
+                }
+                None => bail!("Folder {} not found in {}", path, self.mailbox),
+            };
+        }
+        self.folder_ids.insert(path.to_owned(), parent.clone());
+        Ok(parent)
+    }
+
+    /// Queues the unread messages of all folders
+    async fn search(&mut self) -> Result<()> {
+        for folder in self.folders.clone() {
+            let id = self.folder_id(&folder, false).await?;
+            let mut url = self.url(&["mailFolders", &id, "messages"])?;
+            url.query_pairs_mut()
+                .append_pair("$filter", "isRead eq false")
+                .append_pair("$select", "id,categories")
+                .append_pair("$top", &PAGE_SIZE.to_string());
+            let mut next = Some(url);
+            let mut found = 0;
+            while let Some(url) = next.take() {
+                let request = self.request(reqwest::Method::GET, url).await?;
+                let page: Page<MessageRef> = serde_json::from_slice(&self.send(request).await?)
+                    .context("Invalid message list")?;
+                found += page.value.len();
+                self.pending.extend(page.value.into_iter().map(|x| Pending {
+                    folder: folder.clone(),
+                    id: x.id,
+                    categories: x.categories,
+                }));
+                next = page.next_link.map(|x| Url::parse(&x)).transpose()?;
+            }
+            log::debug!("{} unread messages in {}", found, folder);
+        }
+        Ok(())
+    }
+
+    /// Downloads the MIME content of the message
+    async fn fetch(&self, id: &str) -> Result<Bytes> {
+        let url = self.url(&["messages", id, "$value"])?;
+        let request = self.request(reqwest::Method::GET, url).await?;
+        self.send(request).await
+    }
+
+    /// Sets the flags and moves the message to the target folder
+    async fn file(&mut self, message: &Pending, target: &str, flags: &[String]) -> Result<()> {
+        let mut flags = flags.to_vec();
+        if target == message.folder {
+            // keep it from being fetched again
+            flags.push("\\Seen".to_owned());
+        }
+        if let Some(body) = update_body(&flags, &message.categories) {
+            let url = self.url(&["messages", &message.id])?;
+            let request = self.request(reqwest::Method::PATCH, url).await?.json(&body);
+            self.send(request).await?;
+        }
+        if target == message.folder {
+            return Ok(());
+        }
+        let destination = self.folder_id(target, true).await?;
+        let url = self.url(&["messages", &message.id, "move"])?;
+        let request = self
+            .request(reqwest::Method::POST, url)
+            .await?
+            .json(&serde_json::json!({ "destinationId": destination }));
+        self.send(request).await?;
+        log::info!("Message moved to folder: {}", target);
+        Ok(())
+    }
+
+    /// Drops the queued messages after a failure while polling
+    fn reset(&mut self, err: anyhow::Error) {
+        log::error!("Can't fetch Graph messages: {:#}", err);
+        self.pending.clear();
+    }
+}
+
+#[async_trait]
+impl Source for GraphSource {
+    async fn next_message(&mut self) -> Result<Option<Message>> {
+        loop {
+            if let Some(pending) = self.pending.pop_front() {
+                let content = match self.fetch(&pending.id).await {
+                    Ok(x) => x,
+                    Err(err) if self.interval.is_some() => {
+                        self.reset(err);
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+                let message = Message {
+                    id: format!("graph:{}/{}", self.mailbox, pending.id),
+                    content,
+                    mailbox: Some(pending.folder.clone()),
+                };
+                self.current = Some(pending);
+                return Ok(Some(message));
+            }
+            if self.searched {
+                let interval = match self.interval {
+                    Some(x) => x,
+                    None => return Ok(None),
+                };
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => (),
+                    _ = self.cancel.cancelled() => return Ok(None),
+                }
+            }
+            self.searched = true;
+            if let Err(err) = self.search().await {
+                if self.interval.is_none() {
+                    return Err(err);
+                }
+                self.reset(err);
+            }
+        }
+    }
+
+    async fn finish(&mut self, message: &Message, result: &ProcessResult) -> Result<()> {
+        let pending = match self.current.take() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let retry = result.errors.iter().any(|x| {
+            matches!(
+                x.category,
+                ErrorCategory::MailStore | ErrorCategory::Cancelled
+            )
+        });
+        if retry {
+            log::warn!("{} stays unread and is fetched again", message.id);
+            return Ok(());
+        }
+        // messages that weren't filed, like with extract_only, stay in place
+        let target = result
+            .mailbox
+            .clone()
+            .unwrap_or_else(|| pending.folder.clone());
+        self.file(&pending, &target, &result.flags).await
+    }
+
+    fn set_cancellation(&mut self, cancel: CancellationToken) {
+        self.cancel = cancel;
+    }
+
+    async fn check(&mut self) -> Result<()> {
+        self.credentials
+            .access_token()
+            .await
+            .context("Can't fetch the Graph access token")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_body() {
+        let flags = vec!["\\Flagged".to_owned(), "invoice".to_owned()];
+        assert_eq!(
+            update_body(&flags, &["Red category".to_owned()]),
+            Some(serde_json::json!({
+                "flag": { "flagStatus": "flagged" },
+                "categories": ["Red category", "invoice"],
+            }))
+        );
+        assert_eq!(
+            update_body(&["\\Seen".to_owned()], &[]),
+            Some(serde_json::json!({ "isRead": true }))
+        );
+        assert_eq!(update_body(&[], &["Red category".to_owned()]), None);
+        assert_eq!(odata_string("Bob's"), "'Bob''s'");
+    }
+}
@@ -0,0 +1,97 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Hooks to extend the message processing.
+//!
+//! A [`ProcessingHook`] is registered on the [`crate::Processor`] and gets
+//! called on each step of the pipeline. All callbacks have a default
+//! implementation, so a hook only implements the steps it is interested in.
+
+use anyhow::Result;
+use mailparse::ParsedMail;
+
+/// Attachment that matched the accepted mimetypes
+#[derive(Debug)]
+pub struct Attachment<'a> {
+    /// File name from the content disposition or a generated one
+    pub file_name: &'a str,
+    pub mimetype: &'a str,
+    /// Decoded body of the attachment
+    pub body: &'a [u8],
+}
+
+/// Callbacks into the processing of a message.
+///
+/// Errors returned by a hook are logged and counted as processing errors,
+/// so the mail ends up with the error flags and mail template.
+pub trait ProcessingHook: Send + Sync {
+    /// Called after the message was parsed successfully
+    fn message_parsed(&self, _message: &ParsedMail) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called after the user was detected. `None` means the unknown user is used.
+    /// The hook may change the user.
+    fn user_resolved(&self, _message: &ParsedMail, _user: &mut Option<String>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for each attachment matching the accepted mimetypes.
+    /// Returning `false` skips the attachment without counting an error.
+    fn attachment_extracted(&self, _attachment: &Attachment) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// Called for each accepted attachment before the output path is rendered.
+    /// Returning a new body replaces the content that gets uploaded.
+    fn transform_attachment(&self, _attachment: &Attachment) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Called with the rendered output path before the attachment is uploaded.
+    /// The hook may change the path. An error skips the upload.
+    fn before_upload(&self, _path: &mut String, _attachment: &Attachment) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called after the upload finished or the retries are exhausted
+    fn after_upload(&self, _path: &str, _result: &Result<()>) {}
+
+    /// Called before the mail is stored in the maildir or imap target.
+    /// The hook may change the target folder and the flags.
+    fn before_mail_store(&self, _target: &mut String, _flags: &mut Vec<String>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Allows sharing a hook with the caller, for example to read collected data
+/// after processing
+impl<T: ProcessingHook + ?Sized> ProcessingHook for std::sync::Arc<T> {
+    fn message_parsed(&self, message: &ParsedMail) -> Result<()> {
+        (**self).message_parsed(message)
+    }
+
+    fn user_resolved(&self, message: &ParsedMail, user: &mut Option<String>) -> Result<()> {
+        (**self).user_resolved(message, user)
+    }
+
+    fn attachment_extracted(&self, attachment: &Attachment) -> Result<bool> {
+        (**self).attachment_extracted(attachment)
+    }
+
+    fn transform_attachment(&self, attachment: &Attachment) -> Result<Option<Vec<u8>>> {
+        (**self).transform_attachment(attachment)
+    }
+
+    fn before_upload(&self, path: &mut String, attachment: &Attachment) -> Result<()> {
+        (**self).before_upload(path, attachment)
+    }
+
+    fn after_upload(&self, path: &str, result: &Result<()>) {
+        (**self).after_upload(path, result)
+    }
+
+    fn before_mail_store(&self, target: &mut String, flags: &mut Vec<String>) -> Result<()> {
+        (**self).before_mail_store(target, flags)
+    }
+}
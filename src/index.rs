@@ -0,0 +1,313 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! SQLite index of processed messages and stored files.
+//!
+//! With `--index-db` every processed message is recorded with its
+//! `Message-ID`, user and status, and every attachment with its path,
+//! content hash and status. Messages recorded as successful are skipped
+//! when they are processed again, so reprocessing a mailbox only stores
+//! the new mails. `--force` processes them anyway.
+//!
+//! The database is opened for each access, so several processes can share
+//! it, SQLite serializes the writes. The `list` and `search` subcommands
+//! query the stored files, see [`FileQuery`].
+
+use crate::ProcessResult;
+use anyhow::{Context, Result};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Time to wait for the lock of another process
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY,
+    time INTEGER NOT NULL,
+    message_id TEXT,
+    source TEXT,
+    user TEXT,
+    vendor TEXT,
+    success INTEGER NOT NULL,
+    errors INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS messages_message_id ON messages (message_id);
+CREATE TABLE IF NOT EXISTS files (
+    id INTEGER PRIMARY KEY,
+    message INTEGER NOT NULL REFERENCES messages (id),
+    time INTEGER NOT NULL,
+    user TEXT,
+    file_name TEXT NOT NULL,
+    mimetype TEXT NOT NULL,
+    path TEXT NOT NULL,
+    sha256 TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    status TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS files_sha256 ON files (sha256);
+CREATE INDEX IF NOT EXISTS files_user ON files (user);
+";
+
+/// Stored file of the index
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct IndexedFile {
+    /// Unix timestamp
+    pub time: i64,
+    pub user: Option<String>,
+    pub file_name: String,
+    /// Rendered output path
+    pub path: String,
+    pub sha256: String,
+    pub size: i64,
+    pub vendor: Option<String>,
+    pub message_id: Option<String>,
+}
+
+/// Filter of the stored files, all set fields must match
+#[derive(Debug, Clone, Default)]
+pub struct FileQuery {
+    pub user: Option<String>,
+    /// Unix timestamp of the oldest file
+    pub since: Option<i64>,
+    /// Case insensitive text in the file name, path, vendor or
+    /// `Message-ID`, or the start of the content hash
+    pub term: Option<String>,
+    pub limit: Option<u32>,
+}
+
+/// Index database at a path
+#[derive(Debug, Clone)]
+pub struct IndexDb {
+    path: PathBuf,
+}
+
+impl IndexDb {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Opens the database and creates the tables on first use
+    fn open(&self) -> Result<Connection> {
+        let conn = Connection::open(&self.path)
+            .with_context(|| format!("Can't open index database {}", self.path.display()))?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.execute_batch(SCHEMA)
+            .with_context(|| format!("Can't create index database {}", self.path.display()))?;
+        Ok(conn)
+    }
+
+    /// Records the message and its attachments
+    pub fn record(&self, result: &ProcessResult) -> Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs() as i64)
+            .unwrap_or_default();
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO messages (time, message_id, source, user, vendor, success, errors)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                time,
+                result.message_id,
+                result.message,
+                result.user,
+                result.vendor,
+                result.is_success(),
+                result.num_errors,
+            ],
+        )?;
+        let message = tx.last_insert_rowid();
+        for file in result.attachments.iter() {
+            let status = serde_json::to_value(file.status)?;
+            tx.execute(
+                "INSERT INTO files (message, time, user, file_name, mimetype, path, sha256, size, status)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    message,
+                    time,
+                    result.user,
+                    file.file_name,
+                    file.mimetype,
+                    file.path,
+                    file.sha256,
+                    file.size as i64,
+                    status.as_str().unwrap_or_default(),
+                ],
+            )?;
+        }
+        tx.commit()
+            .with_context(|| format!("Can't write index database {}", self.path.display()))?;
+        Ok(())
+    }
+
+    /// Returns true if a message with the `Message-ID` was processed
+    /// successfully before
+    pub fn is_processed(&self, message_id: &str) -> Result<bool> {
+        if !self.path.exists() {
+            return Ok(false);
+        }
+        let conn = self.open()?;
+        let found = conn
+            .query_row(
+                "SELECT 1 FROM messages WHERE message_id = ?1 AND success = 1 LIMIT 1",
+                params![message_id],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    /// Returns the stored files matching the query, newest first
+    pub fn files(&self, query: &FileQuery) -> Result<Vec<IndexedFile>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let mut sql =
+            "SELECT f.time, f.user, f.file_name, f.path, f.sha256, f.size, m.vendor, m.message_id
+             FROM files f JOIN messages m ON m.id = f.message
+             WHERE f.status = 'stored'"
+                .to_owned();
+        let mut values: Vec<rusqlite::types::Value> = Vec::new();
+        if let Some(user) = &query.user {
+            values.push(user.clone().into());
+            sql.push_str(&format!(" AND f.user = ?{}", values.len()));
+        }
+        if let Some(since) = query.since {
+            values.push(since.into());
+            sql.push_str(&format!(" AND f.time >= ?{}", values.len()));
+        }
+        if let Some(term) = &query.term {
+            values.push(format!("%{}%", escape_like(term)).into());
+            let like = values.len();
+            values.push(format!("{}%", escape_like(&term.to_lowercase())).into());
+            sql.push_str(&format!(
+                " AND (f.file_name LIKE ?{like} ESCAPE '\\' OR f.path LIKE ?{like} ESCAPE '\\'
+                  OR m.vendor LIKE ?{like} ESCAPE '\\' OR m.message_id LIKE ?{like} ESCAPE '\\'
+                  OR f.sha256 LIKE ?{hash} ESCAPE '\\')",
+                like = like,
+                hash = values.len()
+            ));
+        }
+        sql.push_str(" ORDER BY f.time DESC, f.id DESC");
+        if let Some(limit) = query.limit {
+            sql.push_str(&format!(" LIMIT {}", limit));
+        }
+        let conn = self.open()?;
+        let mut statement = conn.prepare(&sql)?;
+        let rows = statement.query_map(params_from_iter(values), |row| {
+            Ok(IndexedFile {
+                time: row.get(0)?,
+                user: row.get(1)?,
+                file_name: row.get(2)?,
+                path: row.get(3)?,
+                sha256: row.get(4)?,
+                size: row.get(5)?,
+                vendor: row.get(6)?,
+                message_id: row.get(7)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+}
+
+/// Escapes the wildcards of a LIKE pattern with a backslash
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Parses `YYYY`, `YYYY-MM` or `YYYY-MM-DD` as the start of the period in
+/// local time
+pub fn parse_since(text: &str) -> Result<i64> {
+    let text = text.trim();
+    let date = match text.len() {
+        4 => format!("{}-01-01", text),
+        7 => format!("{}-01", text),
+        _ => text.to_owned(),
+    };
+    let date = chrono::NaiveDate::parse_from_str(&date, "%Y-%m-%d").with_context(|| {
+        format!(
+            "Invalid date {}, expected YYYY, YYYY-MM or YYYY-MM-DD",
+            text
+        )
+    })?;
+    let start = date.and_hms_opt(0, 0, 0).context("Invalid date")?;
+    let local = chrono::TimeZone::from_local_datetime(&chrono::Local, &start)
+        .earliest()
+        .context("Invalid local time")?;
+    Ok(local.timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileResult, FileStatus};
+
+    #[test]
+    fn test_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = IndexDb::new(dir.path().join("index.sqlite"));
+        assert!(!index.is_processed("abc@example.com").unwrap());
+
+        let mut result = ProcessResult {
+            message_id: Some("abc@example.com".to_owned()),
+            user: Some("alice".to_owned()),
+            ..Default::default()
+        };
+        result.add_file(FileResult {
+            file_name: "invoice.pdf".to_owned(),
+            path: "alice/invoice.pdf".to_owned(),
+            status: FileStatus::Stored,
+            ..Default::default()
+        });
+        index.record(&result).unwrap();
+        assert!(index.is_processed("abc@example.com").unwrap());
+        assert!(!index.is_processed("other@example.com").unwrap());
+
+        let conn = index.open().unwrap();
+        let status: String = conn
+            .query_row(
+                "SELECT status FROM files WHERE path = 'alice/invoice.pdf'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(status, "stored");
+
+        let query = |user: Option<&str>, term: Option<&str>| {
+            let query = FileQuery {
+                user: user.map(|x| x.to_owned()),
+                term: term.map(|x| x.to_owned()),
+                ..Default::default()
+            };
+            index.files(&query).unwrap().len()
+        };
+        assert_eq!(query(Some("alice"), None), 1);
+        assert_eq!(query(Some("bob"), None), 0);
+        assert_eq!(query(None, Some("INVOICE")), 1);
+        assert_eq!(query(None, Some("invoice_")), 0);
+    }
+
+    #[test]
+    fn test_parse_since() {
+        assert_eq!(
+            parse_since("2024-01").unwrap(),
+            parse_since("2024-01-01").unwrap()
+        );
+        assert_eq!(
+            parse_since("2024").unwrap(),
+            parse_since("2024-01-01").unwrap()
+        );
+        assert!(parse_since("2024-01").unwrap() < parse_since("2024-02").unwrap());
+        assert!(parse_since("January").is_err());
+    }
+}
@@ -0,0 +1,284 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Data of structured invoices, read from the XML of XRechnung and
+//! ZUGFeRD (UN/CEFACT CII) and UBL invoices
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use serde::Serialize;
+
+/// Fields found in an invoice document
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct InvoiceData {
+    /// Syntax of the document, see [`InvoiceFormat`]
+    pub format: Option<InvoiceFormat>,
+    /// Invoice number given by the seller
+    pub number: Option<String>,
+    /// Name of the seller
+    pub seller: Option<String>,
+    /// Total amount including taxes
+    pub amount: Option<f64>,
+    /// ISO 4217 currency code of the amount
+    pub currency: Option<String>,
+    /// Issue date of the invoice as YYYY-MM-DD
+    pub date: Option<String>,
+}
+
+/// Syntax of a structured invoice
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvoiceFormat {
+    /// UN/CEFACT Cross Industry Invoice
+    Cii,
+    /// CII with a ZUGFeRD or Factur-X profile
+    Zugferd,
+    /// OASIS Universal Business Language
+    Ubl,
+    /// CII or UBL with the XRechnung profile
+    Xrechnung,
+}
+
+impl std::fmt::Display for InvoiceFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            InvoiceFormat::Cii => "cii",
+            InvoiceFormat::Zugferd => "zugferd",
+            InvoiceFormat::Ubl => "ubl",
+            InvoiceFormat::Xrechnung => "xrechnung",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl InvoiceData {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Takes the fields missing in this data from the other
+    pub fn fill(&mut self, other: InvoiceData) {
+        self.format = self.format.or(other.format);
+        self.number = self.number.take().or(other.number);
+        self.seller = self.seller.take().or(other.seller);
+        self.amount = self.amount.or(other.amount);
+        self.currency = self.currency.take().or(other.currency);
+        self.date = self.date.take().or(other.date);
+    }
+}
+
+/// Elements containing the total, by priority. The gross total is
+/// preferred over the amount due, which may be reduced by prepayments.
+const TOTAL_ELEMENTS: [&[u8]; 4] = [
+    b"GrandTotalAmount",
+    b"TaxInclusiveAmount",
+    b"DuePayableAmount",
+    b"PayableAmount",
+];
+
+/// Reads the invoice data of the attachment. Only XML documents are
+/// supported, other types return empty data.
+pub fn extract(mimetype: &str, body: &[u8]) -> InvoiceData {
+    if !is_xml(mimetype) {
+        return InvoiceData::default();
+    }
+    parse_xml(body).unwrap_or_default()
+}
+
+/// True for the XML mimetypes invoices are sent with
+pub fn is_xml(mimetype: &str) -> bool {
+    mimetype.ends_with("/xml") || mimetype.ends_with("+xml")
+}
+
+/// True if the XML document is a CII or UBL invoice
+pub fn is_invoice(body: &[u8]) -> bool {
+    parse_xml(body).map_or(false, |x| x.format.is_some())
+}
+
+/// Parses CII and UBL invoices. Returns `None` for malformed XML, other
+/// XML documents return empty data.
+pub fn parse_xml(body: &[u8]) -> Option<InvoiceData> {
+    let mut reader = Reader::from_reader(body);
+    reader.trim_text(true);
+    let mut rv = InvoiceData::default();
+    let mut amount_priority = TOTAL_ELEMENTS.len();
+    // local names of the open elements
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    let mut currency_attribute = None;
+    loop {
+        match reader.read_event().ok()? {
+            Event::Start(element) => {
+                if path.is_empty() {
+                    rv.format = match element.local_name().as_ref() {
+                        b"CrossIndustryInvoice" => Some(InvoiceFormat::Cii),
+                        b"Invoice" | b"CreditNote" => Some(InvoiceFormat::Ubl),
+                        _ => return Some(InvoiceData::default()),
+                    };
+                }
+                path.push(element.local_name().as_ref().to_vec());
+                currency_attribute = element
+                    .attributes()
+                    .filter_map(|x| x.ok())
+                    .find(|x| x.key.local_name().as_ref() == b"currencyID")
+                    .and_then(|x| x.unescape_value().ok().map(|x| x.into_owned()));
+            }
+            Event::Text(text) => {
+                let text = text.unescape().ok()?;
+                let text = text.trim();
+                let current = path.last().map(|x| x.as_slice()).unwrap_or_default();
+                let parent = path
+                    .len()
+                    .checked_sub(2)
+                    .map(|x| path[x].as_slice())
+                    .unwrap_or_default();
+                if let Some(priority) = TOTAL_ELEMENTS.iter().position(|x| *x == current) {
+                    if priority < amount_priority {
+                        if let Ok(amount) = text.parse::<f64>() {
+                            amount_priority = priority;
+                            rv.amount = Some(amount);
+                            if currency_attribute.is_some() {
+                                rv.currency = currency_attribute.clone();
+                            }
+                        }
+                    }
+                } else if (current == b"ID" && parent == b"ExchangedDocument")
+                    || (current == b"ID" && path.len() == 2)
+                {
+                    // CII and UBL, other IDs belong to parties and lines
+                    rv.number = Some(text.to_owned());
+                } else if (current == b"Name" && parent == b"SellerTradeParty")
+                    || (current == b"Name"
+                        && parent == b"PartyName"
+                        && path.iter().any(|x| x == b"AccountingSupplierParty"))
+                {
+                    rv.seller = Some(text.to_owned());
+                } else if current == b"RegistrationName"
+                    && rv.seller.is_none()
+                    && path.iter().any(|x| x == b"AccountingSupplierParty")
+                {
+                    // UBL parties may only have a legal name
+                    rv.seller = Some(text.to_owned());
+                } else if (current == b"ID"
+                    && parent == b"GuidelineSpecifiedDocumentContextParameter")
+                    || (current == b"CustomizationID" && path.len() == 2)
+                {
+                    let profile = text.to_lowercase();
+                    if profile.contains("xrechnung") {
+                        rv.format = Some(InvoiceFormat::Xrechnung);
+                    } else if profile.contains("zugferd") || profile.contains("factur-x") {
+                        rv.format = Some(InvoiceFormat::Zugferd);
+                    }
+                } else if current == b"InvoiceCurrencyCode" || current == b"DocumentCurrencyCode" {
+                    rv.currency = Some(text.to_owned());
+                } else if current == b"DateTimeString" && parent == b"IssueDateTime" {
+                    // CII format 102 is YYYYMMDD
+                    rv.date = parse_date(text, "%Y%m%d");
+                } else if current == b"IssueDate" && path.len() == 2 {
+                    // UBL, other IssueDate elements reference earlier invoices
+                    rv.date = parse_date(text, "%Y-%m-%d");
+                }
+            }
+            Event::End(_) => {
+                path.pop();
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+    Some(rv)
+}
+
+/// Normalizes the date to YYYY-MM-DD
+fn parse_date(text: &str, format: &str) -> Option<String> {
+    chrono::NaiveDate::parse_from_str(text, format)
+        .ok()
+        .map(|x| x.format("%Y-%m-%d").to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_xml() {
+        let cii = br#"<?xml version="1.0" encoding="UTF-8"?>
+            <rsm:CrossIndustryInvoice xmlns:rsm="urn:un:unece:uncefact:data:standard:CrossIndustryInvoice:100"
+                xmlns:ram="urn:un:unece:uncefact:data:standard:ReusableAggregateBusinessInformationEntity:100"
+                xmlns:udt="urn:un:unece:uncefact:data:standard:UnqualifiedDataType:100">
+              <rsm:ExchangedDocumentContext>
+                <ram:BusinessProcessSpecifiedDocumentContextParameter>
+                  <ram:ID>urn:fdc:peppol.eu:2017:poacc:billing:01:1.0</ram:ID>
+                </ram:BusinessProcessSpecifiedDocumentContextParameter>
+                <ram:GuidelineSpecifiedDocumentContextParameter>
+                  <ram:ID>urn:cen.eu:en16931:2017#compliant#urn:xoev-de:kosit:standard:xrechnung_2.3</ram:ID>
+                </ram:GuidelineSpecifiedDocumentContextParameter>
+              </rsm:ExchangedDocumentContext>
+              <rsm:ExchangedDocument>
+                <ram:ID>RE-2023-0042</ram:ID>
+                <ram:IssueDateTime>
+                  <udt:DateTimeString format="102">20230115</udt:DateTimeString>
+                </ram:IssueDateTime>
+              </rsm:ExchangedDocument>
+              <rsm:SupplyChainTradeTransaction>
+                <ram:ApplicableHeaderTradeAgreement>
+                  <ram:SellerTradeParty>
+                    <ram:ID>4711</ram:ID>
+                    <ram:Name>ACME GmbH</ram:Name>
+                  </ram:SellerTradeParty>
+                  <ram:BuyerTradeParty>
+                    <ram:Name>B1 Systems GmbH</ram:Name>
+                  </ram:BuyerTradeParty>
+                </ram:ApplicableHeaderTradeAgreement>
+                <ram:ApplicableHeaderTradeSettlement>
+                  <ram:InvoiceCurrencyCode>EUR</ram:InvoiceCurrencyCode>
+                  <ram:SpecifiedTradeSettlementHeaderMonetarySummation>
+                    <ram:TaxBasisTotalAmount>10000.00</ram:TaxBasisTotalAmount>
+                    <ram:GrandTotalAmount>11900.00</ram:GrandTotalAmount>
+                    <ram:DuePayableAmount>5000.00</ram:DuePayableAmount>
+                  </ram:SpecifiedTradeSettlementHeaderMonetarySummation>
+                </ram:ApplicableHeaderTradeSettlement>
+              </rsm:SupplyChainTradeTransaction>
+            </rsm:CrossIndustryInvoice>"#;
+        let data = extract("application/xml", cii);
+        assert_eq!(data.amount, Some(11900.0));
+        assert_eq!(data.currency.as_deref(), Some("EUR"));
+        assert_eq!(data.date.as_deref(), Some("2023-01-15"));
+        assert_eq!(data.number.as_deref(), Some("RE-2023-0042"));
+        assert_eq!(data.seller.as_deref(), Some("ACME GmbH"));
+        assert_eq!(data.format, Some(InvoiceFormat::Xrechnung));
+
+        let ubl = br#"<Invoice xmlns="urn:oasis:names:specification:ubl:schema:xsd:Invoice-2"
+                xmlns:cbc="urn:oasis:names:specification:ubl:schema:xsd:CommonBasicComponents-2"
+                xmlns:cac="urn:oasis:names:specification:ubl:schema:xsd:CommonAggregateComponents-2">
+              <cbc:ID>INV-17</cbc:ID>
+              <cbc:IssueDate>2023-02-28</cbc:IssueDate>
+              <cac:BillingReference>
+                <cac:InvoiceDocumentReference>
+                  <cbc:IssueDate>2022-12-01</cbc:IssueDate>
+                </cac:InvoiceDocumentReference>
+              </cac:BillingReference>
+              <cac:AccountingSupplierParty>
+                <cac:Party>
+                  <cac:PartyIdentification><cbc:ID>CH-123</cbc:ID></cac:PartyIdentification>
+                  <cac:PartyLegalEntity>
+                    <cbc:RegistrationName>Muster AG</cbc:RegistrationName>
+                  </cac:PartyLegalEntity>
+                </cac:Party>
+              </cac:AccountingSupplierParty>
+              <cac:LegalMonetaryTotal>
+                <cbc:PayableAmount currencyID="CHF">250.50</cbc:PayableAmount>
+              </cac:LegalMonetaryTotal>
+            </Invoice>"#;
+        let data = extract("text/xml", ubl);
+        assert_eq!(data.amount, Some(250.5));
+        assert_eq!(data.currency.as_deref(), Some("CHF"));
+        assert_eq!(data.date.as_deref(), Some("2023-02-28"));
+        assert_eq!(data.number.as_deref(), Some("INV-17"));
+        assert_eq!(data.seller.as_deref(), Some("Muster AG"));
+        assert_eq!(data.format, Some(InvoiceFormat::Ubl));
+
+        assert!(extract("application/pdf", b"%PDF-1.4").is_empty());
+        assert!(extract("application/xml", b"<a><b></a>").is_empty());
+        assert!(extract("application/xml", b"<Order><ID>1</ID></Order>").is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Mapping of detected users to directory accounts.
+//!
+//! Aliases like `invoice+buchhaltung@…` often differ from the account
+//! names. With `--ldap-url` the user found by the sources of `user_from`
+//! and the `To` address are looked up with `ldap_filter`, `{user}` and
+//! `{address}` are replaced by the escaped values:
+//!
+//! ```toml
+//! ldap_url = "ldaps://dc.example.com"
+//! ldap_base_dn = "ou=people,dc=example,dc=com"
+//! ldap_filter = "(|(proxyAddresses=smtp:{address})(sAMAccountName={user}))"
+//! ldap_user_attribute = "sAMAccountName"
+//! ldap_department_attribute = "department"
+//! ```
+//!
+//! The `ldap_user_attribute` of the first entry found is the user, the
+//! department is available as `department` in the templates. Requires the
+//! `ldap` feature.
+
+use crate::Config;
+use anyhow::Result;
+
+pub const DEFAULT_LDAP_FILTER: &str = "(|(mail={address})(uid={user}))";
+pub const DEFAULT_LDAP_USER_ATTRIBUTE: &str = "uid";
+
+/// Seconds to wait for the directory server
+#[cfg(feature = "ldap")]
+const TIMEOUT: u64 = 10;
+
+/// Account found in the directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryUser {
+    pub user: String,
+    pub department: Option<String>,
+}
+
+/// Settings of the directory lookup
+#[derive(Debug, Clone)]
+pub struct LdapLookup {
+    url: String,
+    bind_dn: Option<String>,
+    bind_password: Option<String>,
+    base_dn: String,
+    filter: String,
+    user_attribute: String,
+    department_attribute: Option<String>,
+}
+
+impl LdapLookup {
+    /// Returns the lookup if `ldap_url` is set
+    pub fn from_config(config: &Config) -> Option<Self> {
+        Some(Self {
+            url: config.ldap_url.clone()?,
+            bind_dn: config.ldap_bind_dn.clone(),
+            bind_password: config.ldap_bind_password.clone(),
+            base_dn: config.ldap_base_dn.clone(),
+            filter: config.ldap_filter.clone(),
+            user_attribute: config.ldap_user_attribute.clone(),
+            department_attribute: config.ldap_department_attribute.clone(),
+        })
+    }
+
+    /// Searches the account of the user or address, returns None if no
+    /// entry matches
+    pub async fn lookup(&self, user: Option<&str>, address: &str) -> Result<Option<DirectoryUser>> {
+        let filter = render_filter(&self.filter, user.unwrap_or_default(), address);
+        log::debug!("Searching {} in {}", &filter, &self.url);
+        self.search(&filter).await
+    }
+
+    #[cfg(feature = "ldap")]
+    async fn search(&self, filter: &str) -> Result<Option<DirectoryUser>> {
+        use anyhow::Context;
+        use ldap3::{LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+
+        let settings =
+            LdapConnSettings::new().set_conn_timeout(std::time::Duration::from_secs(TIMEOUT));
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.url)
+            .await
+            .with_context(|| format!("Can't connect to {}", &self.url))?;
+        ldap3::drive!(conn);
+        if let Some(bind_dn) = &self.bind_dn {
+            ldap.simple_bind(bind_dn, self.bind_password.as_deref().unwrap_or_default())
+                .await?
+                .success()
+                .with_context(|| format!("Can't bind as {}", bind_dn))?;
+        }
+        let mut attributes = vec![self.user_attribute.as_str()];
+        attributes.extend(self.department_attribute.as_deref());
+        let (entries, _) = ldap
+            .search(&self.base_dn, Scope::Subtree, filter, attributes)
+            .await?
+            .success()
+            .context("LDAP search failed")?;
+        let _ = ldap.unbind().await;
+        let first_value = |entry: &SearchEntry, attribute: &str| {
+            entry.attrs.get(attribute).and_then(|x| x.first()).cloned()
+        };
+        for entry in entries.into_iter().map(SearchEntry::construct) {
+            if let Some(user) = first_value(&entry, &self.user_attribute) {
+                let department = self
+                    .department_attribute
+                    .as_deref()
+                    .and_then(|x| first_value(&entry, x));
+                return Ok(Some(DirectoryUser { user, department }));
+            }
+        }
+        Ok(None)
+    }
+
+    #[cfg(not(feature = "ldap"))]
+    async fn search(&self, _filter: &str) -> Result<Option<DirectoryUser>> {
+        anyhow::bail!("ldap_url configured, but built without the ldap feature")
+    }
+}
+
+/// Replaces `{user}` and `{address}` with the values escaped for a filter
+pub fn render_filter(filter: &str, user: &str, address: &str) -> String {
+    filter
+        .replace("{user}", &escape(user))
+        .replace("{address}", &escape(address))
+}
+
+/// Escapes the special characters of RFC 4515
+fn escape(value: &str) -> String {
+    let mut rv = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '*' | '(' | ')' | '\\' | '\0' => rv.push_str(&format!("\\{:02x}", c as u32)),
+            _ => rv.push(c),
+        }
+    }
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_filter() {
+        assert_eq!(
+            render_filter(DEFAULT_LDAP_FILTER, "alice", "invoice+alice@example.com"),
+            "(|(mail=invoice+alice@example.com)(uid=alice))"
+        );
+        assert_eq!(
+            render_filter("(cn={user})", "*)(uid=*", ""),
+            "(cn=\\2a\\29\\28uid=\\2a)"
+        );
+    }
+}
@@ -0,0 +1,520 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! LMTP and SMTP server source of the [`Pipeline`](crate::pipeline::Pipeline).
+//!
+//! The MTA, like Postfix, delivers the mails over the socket and gets the
+//! reply after the mail was processed. Failed uploads and mail stores are
+//! answered with a temporary error, so the MTA keeps the mail in its queue
+//! and retries the delivery instead of losing the invoice. The mail sinks are
+//! skipped after a failed upload, so the redelivery doesn't store the mail
+//! twice. If only the mail sinks failed after one of them stored the mail,
+//! it is accepted and filed with the error flags.
+//!
+//! Postfix `main.cf`:
+//!
+//! ```text
+//! mailbox_transport = lmtp:inet:127.0.0.1:2525
+//! ```
+
+use crate::pipeline::{Message, Source};
+use crate::{CancellationToken, ErrorCategory, ProcessResult};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::sync::{mpsc, oneshot};
+
+/// Default address of the `serve` subcommand
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:2525";
+
+/// Mails larger than this are rejected
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Longest command line accepted, RFC 5321 allows 512 bytes
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// Mails accepted by the connections but not yet processed
+const QUEUE_LENGTH: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// RFC 2033, one reply for each recipient after the data
+    #[default]
+    Lmtp,
+    /// RFC 5321, one reply for all recipients
+    Smtp,
+}
+
+/// Reply of the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub code: u16,
+    pub text: String,
+}
+
+impl Reply {
+    fn new(code: u16, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: text.into(),
+        }
+    }
+
+    /// Temporary error if the mail may be stored by a later delivery and
+    /// no mail sink stored it, otherwise the mail is accepted, failed mails
+    /// are filed with the error flags
+    pub fn from_result(result: &ProcessResult) -> Self {
+        let temporary = result.errors.iter().find(|x| {
+            matches!(
+                x.category,
+                ErrorCategory::Storage
+                    | ErrorCategory::MailStore
+                    | ErrorCategory::Output
+                    | ErrorCategory::Cancelled
+            )
+        });
+        // stored attachments are overwritten by the redelivery, the mail
+        // sinks would store the mail twice
+        match temporary {
+            Some(error) if result.mail_sinks.is_empty() => {
+                Self::new(451, format!("4.3.0 {}", first_line(&error.message)))
+            }
+            _ if result.is_success() => Self::new(250, "2.0.0 Stored"),
+            _ => Self::new(250, "2.0.0 Filed with errors"),
+        }
+    }
+
+    fn line(&self) -> String {
+        format!("{} {}\r\n", self.code, self.text)
+    }
+}
+
+fn first_line(text: &str) -> &str {
+    text.lines().next().unwrap_or_default()
+}
+
+/// Mail received by a connection, waiting for its reply
+struct Delivery {
+    message: Message,
+    reply: oneshot::Sender<Reply>,
+}
+
+/// Accepts mails from the MTA. The mails of all connections are processed
+/// one after another, the connection waits for the result before replying.
+pub struct LmtpSource {
+    receiver: mpsc::Receiver<Delivery>,
+    /// Reply channel of the mail returned by the last `next_message`
+    current: Option<oneshot::Sender<Reply>>,
+    cancel: CancellationToken,
+}
+
+impl std::fmt::Debug for LmtpSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LmtpSource").finish_non_exhaustive()
+    }
+}
+
+impl LmtpSource {
+    /// Listens on `host:port` or `unix:/path/to/socket`
+    pub async fn bind(listen: &str, protocol: Protocol) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel(QUEUE_LENGTH);
+        let counter = Arc::new(AtomicU64::new(0));
+        match listen.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => {
+                // a socket of an earlier run blocks the bind
+                let _ = std::fs::remove_file(path);
+                let listener = tokio::net::UnixListener::bind(path)
+                    .with_context(|| format!("Can't listen on {}", listen))?;
+                tokio::spawn(async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((stream, _)) => spawn_session(stream, protocol, &sender, &counter),
+                            Err(err) => log::error!("Can't accept connection: {}", err),
+                        }
+                    }
+                });
+            }
+            #[cfg(not(unix))]
+            Some(_) => anyhow::bail!("Unix sockets are not supported on this platform"),
+            None => {
+                let listener = tokio::net::TcpListener::bind(listen)
+                    .await
+                    .with_context(|| format!("Can't listen on {}", listen))?;
+                tokio::spawn(async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((stream, peer)) => {
+                                log::debug!("Connection from {}", peer);
+                                spawn_session(stream, protocol, &sender, &counter)
+                            }
+                            Err(err) => log::error!("Can't accept connection: {}", err),
+                        }
+                    }
+                });
+            }
+        }
+        log::info!("Listening on {}", listen);
+        Ok(Self {
+            receiver,
+            current: None,
+            cancel: CancellationToken::new(),
+        })
+    }
+}
+
+fn spawn_session<S>(
+    stream: S,
+    protocol: Protocol,
+    sender: &mpsc::Sender<Delivery>,
+    counter: &Arc<AtomicU64>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let sender = sender.clone();
+    let counter = counter.clone();
+    tokio::spawn(async move {
+        if let Err(err) = Session::new(protocol, sender, counter).run(stream).await {
+            log::warn!("Connection closed: {:#}", err);
+        }
+    });
+}
+
+#[async_trait]
+impl Source for LmtpSource {
+    async fn next_message(&mut self) -> Result<Option<Message>> {
+        let delivery = tokio::select! {
+            delivery = self.receiver.recv() => delivery,
+            _ = self.cancel.cancelled() => None,
+        };
+        Ok(delivery.map(|delivery| {
+            self.current = Some(delivery.reply);
+            delivery.message
+        }))
+    }
+
+    async fn finish(&mut self, message: &Message, result: &ProcessResult) -> Result<()> {
+        if let Some(reply) = self.current.take() {
+            if reply.send(Reply::from_result(result)).is_err() {
+                log::warn!("Client of {} disconnected before the reply", message.id);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_cancellation(&mut self, cancel: CancellationToken) {
+        self.cancel = cancel;
+    }
+
+    fn redelivers(&self) -> bool {
+        true
+    }
+}
+
+/// State of one connection
+struct Session {
+    protocol: Protocol,
+    sender: mpsc::Sender<Delivery>,
+    counter: Arc<AtomicU64>,
+    greeted: bool,
+    from: Option<String>,
+    recipients: Vec<String>,
+}
+
+impl Session {
+    fn new(protocol: Protocol, sender: mpsc::Sender<Delivery>, counter: Arc<AtomicU64>) -> Self {
+        Self {
+            protocol,
+            sender,
+            counter,
+            greeted: false,
+            from: None,
+            recipients: Vec::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.from = None;
+        self.recipients.clear();
+    }
+
+    async fn run<S>(mut self, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = BufReader::new(stream);
+        let greeting = match self.protocol {
+            Protocol::Lmtp => "220 invoice2storage LMTP ready\r\n",
+            Protocol::Smtp => "220 invoice2storage ESMTP ready\r\n",
+        };
+        stream.write_all(greeting.as_bytes()).await?;
+        loop {
+            stream.flush().await?;
+            let line = match read_line(&mut stream).await? {
+                Some(x) => x,
+                None => return Ok(()),
+            };
+            let line = String::from_utf8_lossy(&line);
+            let (verb, argument) = line
+                .split_once(' ')
+                .map(|(verb, argument)| (verb, argument.trim()))
+                .unwrap_or((line.as_ref(), ""));
+            let verb = verb.to_ascii_uppercase();
+            let reply = match verb.as_str() {
+                "LHLO" if self.protocol == Protocol::Lmtp => self.hello(true),
+                "EHLO" if self.protocol == Protocol::Smtp => self.hello(true),
+                "HELO" if self.protocol == Protocol::Smtp => self.hello(false),
+                "MAIL" => self.mail(argument),
+                "RCPT" => self.rcpt(argument),
+                "DATA" => {
+                    if let Err(reply) = self.check_data() {
+                        reply
+                    } else {
+                        stream
+                            .write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")
+                            .await?;
+                        stream.flush().await?;
+                        let content = read_data(&mut stream).await?;
+                        let replies = self.deliver(content).await;
+                        for reply in replies.iter() {
+                            stream.write_all(reply.line().as_bytes()).await?;
+                        }
+                        continue;
+                    }
+                }
+                "RSET" => {
+                    self.reset();
+                    "250 2.0.0 Ok\r\n".to_owned()
+                }
+                "NOOP" => "250 2.0.0 Ok\r\n".to_owned(),
+                "VRFY" => "252 2.5.2 Cannot verify\r\n".to_owned(),
+                "QUIT" => {
+                    stream.write_all(b"221 2.0.0 Bye\r\n").await?;
+                    stream.flush().await?;
+                    return Ok(());
+                }
+                _ => "502 5.5.2 Command not implemented\r\n".to_owned(),
+            };
+            stream.write_all(reply.as_bytes()).await?;
+        }
+    }
+
+    fn hello(&mut self, extended: bool) -> String {
+        self.greeted = true;
+        self.reset();
+        if extended {
+            format!(
+                "250-invoice2storage\r\n250-PIPELINING\r\n250-8BITMIME\r\n\
+                 250-ENHANCEDSTATUSCODES\r\n250 SIZE {}\r\n",
+                MAX_MESSAGE_SIZE
+            )
+        } else {
+            "250 invoice2storage\r\n".to_owned()
+        }
+    }
+
+    fn mail(&mut self, argument: &str) -> String {
+        if !self.greeted {
+            return "503 5.5.1 Send LHLO or EHLO first\r\n".to_owned();
+        }
+        if self.from.is_some() {
+            return "503 5.5.1 Nested MAIL command\r\n".to_owned();
+        }
+        match parse_path(argument, "FROM:") {
+            Some(address) => {
+                self.from = Some(address);
+                "250 2.1.0 Ok\r\n".to_owned()
+            }
+            None => "501 5.5.4 Syntax: MAIL FROM:<address>\r\n".to_owned(),
+        }
+    }
+
+    fn rcpt(&mut self, argument: &str) -> String {
+        if self.from.is_none() {
+            return "503 5.5.1 Need MAIL command\r\n".to_owned();
+        }
+        match parse_path(argument, "TO:") {
+            Some(address) if !address.is_empty() => {
+                self.recipients.push(address);
+                "250 2.1.5 Ok\r\n".to_owned()
+            }
+            _ => "501 5.5.4 Syntax: RCPT TO:<address>\r\n".to_owned(),
+        }
+    }
+
+    fn check_data(&self) -> Result<(), String> {
+        if self.recipients.is_empty() {
+            return Err("503 5.5.1 Need RCPT command\r\n".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Processes the mail and returns one reply for each recipient with
+    /// LMTP, one reply with SMTP
+    async fn deliver(&mut self, content: Option<Vec<u8>>) -> Vec<Reply> {
+        let count = match self.protocol {
+            Protocol::Lmtp => self.recipients.len(),
+            Protocol::Smtp => 1,
+        };
+        let reply = match content {
+            Some(content) => self.process(content).await,
+            None => Reply::new(552, "5.3.4 Message too big"),
+        };
+        self.reset();
+        vec![reply; count]
+    }
+
+    async fn process(&self, content: Vec<u8>) -> Reply {
+        let id = self.counter.fetch_add(1, Ordering::Relaxed) + 1;
+        // the envelope is kept like a local delivery agent does, the
+        // recipient selects the tenant
+        let mut mail = format!(
+            "Return-Path: <{}>\r\nDelivered-To: {}\r\n",
+            self.from.as_deref().unwrap_or_default(),
+            self.recipients[0]
+        )
+        .into_bytes();
+        mail.extend_from_slice(&content);
+        let (reply, receiver) = oneshot::channel();
+        let delivery = Delivery {
+            message: Message {
+                id: format!("lmtp:{}", id),
+                content: Bytes::from(mail),
+                mailbox: None,
+            },
+            reply,
+        };
+        if self.sender.send(delivery).await.is_err() {
+            return Reply::new(421, "4.3.2 Service shutting down");
+        }
+        receiver
+            .await
+            .unwrap_or_else(|_| Reply::new(451, "4.3.0 Processing aborted"))
+    }
+}
+
+/// Address of `FROM:<address>` or `TO:<address>`, parameters like `SIZE`
+/// are ignored
+fn parse_path(argument: &str, prefix: &str) -> Option<String> {
+    let head = argument.get(..prefix.len())?;
+    if !head.eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let path = argument[prefix.len()..].trim_start();
+    let path = path.strip_prefix('<')?;
+    let end = path.find('>')?;
+    Some(path[..end].to_owned())
+}
+
+/// Reads a line without the line ending, `None` at the end of the stream
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let read = (&mut *reader)
+        .take(MAX_LINE_LENGTH as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        anyhow::bail!("Line too long");
+    }
+    while matches!(line.last(), Some(b'\n' | b'\r')) {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Reads the mail up to the line with a single dot and removes the dot
+/// stuffing. Returns `None` if the mail is larger than the limit, the
+/// data is read to the end anyway to keep the session in sync.
+async fn read_data<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut content = Vec::new();
+    let mut too_big = false;
+    loop {
+        let mut line = Vec::new();
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+            anyhow::bail!("Connection closed while reading data");
+        }
+        if line == b".\r\n" || line == b".\n" {
+            break;
+        }
+        let line = line.strip_prefix(b".").unwrap_or(&line);
+        if content.len() + line.len() > MAX_MESSAGE_SIZE {
+            too_big = true;
+            content.clear();
+        }
+        if !too_big {
+            content.extend_from_slice(line);
+        }
+    }
+    Ok((!too_big).then_some(content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lmtp_session() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (sender, mut receiver) = mpsc::channel(1);
+        let session = Session::new(Protocol::Lmtp, sender, Arc::new(AtomicU64::new(0)));
+        tokio::spawn(session.run(server));
+        let handler = tokio::spawn(async move {
+            let delivery: Delivery = receiver.recv().await.unwrap();
+            let content = String::from_utf8_lossy(&delivery.message.content).into_owned();
+            assert_eq!(
+                content,
+                "Return-Path: <sender@example.com>\r\nDelivered-To: a@example.com\r\n\
+                 Subject: test\r\n\r\n.body\r\n"
+            );
+            let mut result = ProcessResult::default();
+            result.add_error(ErrorCategory::Storage, "webdav: 503");
+            delivery.reply.send(Reply::from_result(&result)).unwrap();
+        });
+
+        let mut client = BufReader::new(client);
+        client
+            .write_all(
+                b"LHLO mta\r\nMAIL FROM:<sender@example.com> SIZE=100\r\n\
+                  RCPT TO:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\n",
+            )
+            .await
+            .unwrap();
+        client
+            .write_all(b"Subject: test\r\n\r\n..body\r\n.\r\nQUIT\r\n")
+            .await
+            .unwrap();
+        let mut codes = Vec::new();
+        while let Some(line) = read_line(&mut client).await.unwrap() {
+            let line = String::from_utf8(line).unwrap();
+            if line.as_bytes().get(3) == Some(&b' ') {
+                codes.push(line[..3].to_owned());
+            }
+        }
+        // greeting, LHLO, MAIL, 2 x RCPT, DATA, one reply for each recipient, QUIT
+        assert_eq!(
+            codes,
+            vec!["220", "250", "250", "250", "250", "354", "451", "451", "221"]
+        );
+        handler.await.unwrap();
+    }
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(
+            parse_path("FROM:<a@example.com> BODY=8BITMIME", "FROM:").as_deref(),
+            Some("a@example.com")
+        );
+        assert_eq!(parse_path("from: <>", "FROM:").as_deref(), Some(""));
+        assert_eq!(parse_path("TO:a@example.com", "TO:"), None);
+    }
+}
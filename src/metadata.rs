@@ -0,0 +1,95 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Metadata files next to the stored attachments.
+//!
+//! With `--metadata-sidecar` a `<path>.json` file is stored next to every
+//! attachment, so document management systems can import the mail data
+//! without parsing the mail:
+//!
+//! ```json
+//! {
+//!   "from": "ACME GmbH <billing@acme.example>",
+//!   "recipients": ["invoice+alice@example.com"],
+//!   "subject": "Your invoice",
+//!   "message_id": "abc@acme.example",
+//!   "date": "2023-02-07T15:52:10+01:00",
+//!   "received": "2023-02-07T15:52:12+01:00",
+//!   "file_name": "invoice.pdf",
+//!   "mimetype": "application/pdf",
+//!   "sha256": "…",
+//!   "size": 51234,
+//!   "user": "alice",
+//!   "path": "alice/invoice.pdf"
+//! }
+//! ```
+
+use serde::Serialize;
+
+/// Suffix appended to the path of the attachment
+pub const SIDECAR_SUFFIX: &str = ".json";
+
+/// Content of the sidecar file
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Metadata {
+    pub from: String,
+    /// Addresses of `To` and `Cc`
+    pub recipients: Vec<String>,
+    pub subject: String,
+    pub message_id: String,
+    /// `Date` header as RFC 3339
+    pub date: String,
+    /// Time of the topmost `Received` header as RFC 3339
+    pub received: String,
+    /// Original file name of the attachment
+    pub file_name: String,
+    pub mimetype: String,
+    pub sha256: String,
+    pub size: usize,
+    pub user: String,
+    /// Name of the archive the file was unpacked from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive: Option<String>,
+    /// Path of the attachment on the storage backend
+    pub path: String,
+}
+
+impl Metadata {
+    /// Copy for the attachment stored at the path
+    pub fn for_path(&self, path: &str) -> Self {
+        Self {
+            path: path.to_owned(),
+            ..self.clone()
+        }
+    }
+
+    /// Pretty printed JSON of the sidecar
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec_pretty(self)
+    }
+}
+
+/// Path of the sidecar of an attachment
+pub fn sidecar_path(path: &str) -> String {
+    format!("{}{}", path, SIDECAR_SUFFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sidecar() {
+        let metadata = Metadata {
+            file_name: "invoice.pdf".to_owned(),
+            size: 3,
+            ..Default::default()
+        }
+        .for_path("alice/invoice.pdf");
+        assert_eq!(sidecar_path(&metadata.path), "alice/invoice.pdf.json");
+        let json: serde_json::Value = serde_json::from_slice(&metadata.to_json().unwrap()).unwrap();
+        assert_eq!(json["path"], "alice/invoice.pdf");
+        assert_eq!(json["size"], 3);
+        assert!(json.get("archive").is_none());
+    }
+}
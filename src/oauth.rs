@@ -0,0 +1,465 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! OAuth2 login for Office 365 and Gmail, which don't accept passwords
+//! for IMAP anymore.
+//!
+//! `invoice2storage auth login` runs the device-code flow and stores the
+//! refresh token in the token file. A refresh token issued otherwise can be
+//! set with `oauth_refresh_token` instead. The IMAP connection exchanges it
+//! for access tokens and authenticates with XOAUTH2.
+
+use crate::Config;
+use anyhow::{bail, Context, Result};
+use resolve_path::PathResolveExt;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default location of the stored tokens
+pub const DEFAULT_TOKEN_FILE: &str = "~/.config/invoice2storage/oauth-token.json";
+
+/// Access tokens are refreshed this long before they expire
+const EXPIRY_MARGIN: u64 = 60;
+
+/// Identity provider of the mailbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OAuthProvider {
+    /// Office 365 / Exchange Online
+    Microsoft,
+    Google,
+}
+
+impl OAuthProvider {
+    fn device_code_url(&self, tenant: &str) -> String {
+        match self {
+            OAuthProvider::Microsoft => format!(
+                "https://login.microsoftonline.com/{}/oauth2/v2.0/devicecode",
+                tenant
+            ),
+            OAuthProvider::Google => "https://oauth2.googleapis.com/device/code".to_owned(),
+        }
+    }
+
+    fn token_url(&self, tenant: &str) -> String {
+        match self {
+            OAuthProvider::Microsoft => {
+                format!(
+                    "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
+                    tenant
+                )
+            }
+            OAuthProvider::Google => "https://oauth2.googleapis.com/token".to_owned(),
+        }
+    }
+
+    /// Scopes for IMAP access and refresh tokens
+    fn scope(&self) -> &'static str {
+        match self {
+            OAuthProvider::Microsoft => {
+                "https://outlook.office.com/IMAP.AccessAsUser.All offline_access"
+            }
+            OAuthProvider::Google => "https://mail.google.com/",
+        }
+    }
+}
+
+/// Tokens stored in the token file
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StoredToken {
+    pub refresh_token: String,
+    #[serde(default)]
+    pub access_token: Option<String>,
+    /// Unix timestamp the access token expires
+    #[serde(default)]
+    pub expires_at: u64,
+}
+
+impl StoredToken {
+    fn is_valid(&self) -> bool {
+        self.access_token.is_some() && self.expires_at > now() + EXPIRY_MARGIN
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceCodeResponse {
+    device_code: String,
+    user_code: String,
+    /// google calls it verification_url
+    #[serde(alias = "verification_url")]
+    verification_uri: String,
+    expires_in: u64,
+    #[serde(default = "default_interval")]
+    interval: u64,
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default()
+}
+
+/// OAuth client of one mailbox
+#[derive(Debug)]
+pub struct OAuthClient {
+    provider: OAuthProvider,
+    client_id: String,
+    client_secret: Option<String>,
+    tenant: String,
+    token_file: PathBuf,
+    /// Configured refresh token, the token file isn't used
+    refresh_token: Option<String>,
+    http: reqwest::Client,
+    token: tokio::sync::Mutex<Option<StoredToken>>,
+}
+
+impl OAuthClient {
+    pub fn new(provider: OAuthProvider, client_id: String, token_file: PathBuf) -> Self {
+        Self {
+            provider,
+            client_id,
+            client_secret: None,
+            tenant: "organizations".to_owned(),
+            token_file,
+            refresh_token: None,
+            http: reqwest::Client::new(),
+            token: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Creates the client if a provider is configured
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let provider = match config.oauth_provider {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let client_id = config
+            .oauth_client_id
+            .clone()
+            .context("oauth_client_id is required for OAuth")?;
+        let token_file = config
+            .oauth_token_file
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_TOKEN_FILE))
+            .resolve()
+            .into_owned();
+        let mut client = Self::new(provider, client_id, token_file);
+        client.client_secret = config.oauth_client_secret.clone();
+        client.refresh_token = config.oauth_refresh_token.clone();
+        if let Some(tenant) = &config.oauth_tenant {
+            client.tenant = tenant.clone();
+        }
+        Ok(Some(client))
+    }
+
+    fn credentials(&self) -> Vec<(&'static str, String)> {
+        let mut form = vec![("client_id", self.client_id.clone())];
+        if let Some(secret) = &self.client_secret {
+            form.push(("client_secret", secret.clone()));
+        }
+        form
+    }
+
+    async fn post(&self, url: &str, form: &[(&'static str, String)]) -> Result<TokenResponse> {
+        let response = self.http.post(url).form(form).send().await?;
+        let body = response.bytes().await?;
+        serde_json::from_slice(&body).context("Invalid token response")
+    }
+
+    /// Runs the device-code flow. The user is asked to open the
+    /// verification url with `prompt`. The refresh token is stored in the
+    /// token file.
+    pub async fn login(&self, prompt: impl Fn(&str, &str)) -> Result<()> {
+        let mut form = self.credentials();
+        form.push(("scope", self.provider.scope().to_owned()));
+        let response = self
+            .http
+            .post(self.provider.device_code_url(&self.tenant))
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()
+            .context("Device code request failed")?;
+        let device: DeviceCodeResponse = serde_json::from_slice(&response.bytes().await?)
+            .context("Invalid device code response")?;
+        prompt(&device.verification_uri, &device.user_code);
+
+        let deadline = now() + device.expires_in;
+        let mut interval = device.interval;
+        let mut form = self.credentials();
+        form.push((
+            "grant_type",
+            "urn:ietf:params:oauth:grant-type:device_code".to_owned(),
+        ));
+        form.push(("device_code", device.device_code.clone()));
+        while now() < deadline {
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            let token = self
+                .post(&self.provider.token_url(&self.tenant), &form)
+                .await?;
+            match token.error.as_deref() {
+                None => return self.store(token, None).await,
+                Some("authorization_pending") => (),
+                Some("slow_down") => interval += 5,
+                Some(error) => bail!(
+                    "Login failed: {} {}",
+                    error,
+                    token.error_description.unwrap_or_default()
+                ),
+            }
+        }
+        bail!("Login timed out, the device code expired")
+    }
+
+    /// Returns a valid access token, refreshing it if needed
+    pub async fn access_token(&self) -> Result<String> {
+        let mut cached = self.token.lock().await;
+        if cached.is_none() {
+            *cached = Some(self.initial_token()?);
+        }
+        if let Some(token) = cached.as_ref().filter(|x| x.is_valid()) {
+            return Ok(token.access_token.clone().unwrap_or_default());
+        }
+        let refresh_token = cached
+            .as_ref()
+            .map(|x| x.refresh_token.clone())
+            .unwrap_or_default();
+        let mut form = self.credentials();
+        form.push(("grant_type", "refresh_token".to_owned()));
+        form.push(("refresh_token", refresh_token.clone()));
+        let response = self
+            .post(&self.provider.token_url(&self.tenant), &form)
+            .await?;
+        if let Some(error) = response.error {
+            bail!(
+                "Can't refresh the OAuth token, run `auth login` again: {} {}",
+                error,
+                response.error_description.unwrap_or_default()
+            );
+        }
+        let token = to_stored(response, Some(refresh_token))?;
+        if self.refresh_token.is_none() {
+            write_token(&self.token_file, &token)?;
+        }
+        let access_token = token.access_token.clone().unwrap_or_default();
+        *cached = Some(token);
+        Ok(access_token)
+    }
+
+    /// The configured refresh token or the one of the token file
+    fn initial_token(&self) -> Result<StoredToken> {
+        match &self.refresh_token {
+            Some(refresh_token) => Ok(StoredToken {
+                refresh_token: refresh_token.clone(),
+                access_token: None,
+                expires_at: 0,
+            }),
+            None => read_token(&self.token_file),
+        }
+    }
+
+    async fn store(&self, response: TokenResponse, refresh_token: Option<String>) -> Result<()> {
+        let token = to_stored(response, refresh_token)?;
+        write_token(&self.token_file, &token)?;
+        *self.token.lock().await = Some(token);
+        Ok(())
+    }
+}
+
+/// Access tokens of an app registration with application permissions,
+/// fetched with the client credentials grant without a user login
+#[derive(Debug)]
+pub struct ClientCredentials {
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+    scope: String,
+    http: reqwest::Client,
+    /// Access token and the unix timestamp it expires
+    token: tokio::sync::Mutex<Option<(String, u64)>>,
+}
+
+impl ClientCredentials {
+    /// App of the Microsoft tenant, the scope is like
+    /// `https://graph.microsoft.com/.default`
+    pub fn microsoft(
+        tenant: &str,
+        client_id: impl Into<String>,
+        client_secret: impl Into<String>,
+        scope: impl Into<String>,
+    ) -> Self {
+        Self {
+            token_url: OAuthProvider::Microsoft.token_url(tenant),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            scope: scope.into(),
+            http: reqwest::Client::new(),
+            token: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Returns a valid access token, a new one is requested shortly
+    /// before it expires
+    pub async fn access_token(&self) -> Result<String> {
+        let mut cached = self.token.lock().await;
+        if let Some((token, _)) = cached.as_ref().filter(|x| x.1 > now() + EXPIRY_MARGIN) {
+            return Ok(token.clone());
+        }
+        let form = [
+            ("client_id", self.client_id.clone()),
+            ("client_secret", self.client_secret.clone()),
+            ("scope", self.scope.clone()),
+            ("grant_type", "client_credentials".to_owned()),
+        ];
+        let response = self.http.post(&self.token_url).form(&form).send().await?;
+        let response: TokenResponse =
+            serde_json::from_slice(&response.bytes().await?).context("Invalid token response")?;
+        if let Some(error) = response.error {
+            bail!(
+                "Can't get an access token for {}: {} {}",
+                self.client_id,
+                error,
+                response.error_description.unwrap_or_default()
+            );
+        }
+        let token = response.access_token.context("No access token received")?;
+        *cached = Some((
+            token.clone(),
+            now() + response.expires_in.unwrap_or_default(),
+        ));
+        Ok(token)
+    }
+}
+
+/// Converts the response, providers may not return a new refresh token
+fn to_stored(response: TokenResponse, refresh_token: Option<String>) -> Result<StoredToken> {
+    let refresh_token = response
+        .refresh_token
+        .or(refresh_token)
+        .context("No refresh token received")?;
+    Ok(StoredToken {
+        refresh_token,
+        expires_at: now() + response.expires_in.unwrap_or_default(),
+        access_token: response.access_token,
+    })
+}
+
+fn read_token(path: &Path) -> Result<StoredToken> {
+    let content = std::fs::read(path).with_context(|| {
+        format!(
+            "Can't read OAuth token {}, run `auth login` first",
+            path.display()
+        )
+    })?;
+    serde_json::from_slice(&content).context("Invalid OAuth token file")
+}
+
+/// Writes the token readable only by the current user
+fn write_token(path: &Path, token: &StoredToken) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options
+            .open(&tmp)
+            .with_context(|| format!("Can't write OAuth token {}", tmp.display()))?;
+        std::io::Write::write_all(&mut file, &serde_json::to_vec(token)?)?;
+    }
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// SASL XOAUTH2 mechanism for IMAP
+pub(crate) struct XOAuth2<'a> {
+    pub user: &'a str,
+    pub access_token: &'a str,
+}
+
+impl imap::Authenticator for XOAuth2<'_> {
+    type Response = String;
+
+    fn process(&self, _challenge: &[u8]) -> Self::Response {
+        format!(
+            "user={}\x01auth=Bearer {}\x01\x01",
+            self.user, self.access_token
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens/oauth.json");
+        let token = StoredToken {
+            refresh_token: "refresh".to_owned(),
+            access_token: Some("access".to_owned()),
+            expires_at: now() + 3600,
+        };
+        write_token(&path, &token).unwrap();
+        let read = read_token(&path).unwrap();
+        assert_eq!(read.refresh_token, "refresh");
+        assert!(read.is_valid());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_configured_refresh_token() {
+        let config = Config::builder()
+            .local_path("/tmp/files")
+            .oauth(OAuthProvider::Microsoft, "client")
+            .oauth_token_file("/nonexistent/oauth.json")
+            .oauth_refresh_token("refresh")
+            .build()
+            .unwrap();
+        let client = OAuthClient::from_config(&config).unwrap().unwrap();
+        let token = client.initial_token().unwrap();
+        assert_eq!(token.refresh_token, "refresh");
+        // the access token is fetched first
+        assert!(!token.is_valid());
+    }
+
+    #[test]
+    fn test_xoauth2() {
+        use imap::Authenticator;
+        let auth = XOAuth2 {
+            user: "invoice@example.com",
+            access_token: "token",
+        };
+        assert_eq!(
+            auth.process(b""),
+            "user=invoice@example.com\x01auth=Bearer token\x01\x01"
+        );
+    }
+}
@@ -0,0 +1,137 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Text recognition of scanned invoices.
+//!
+//! With `--ocr` image attachments are passed to tesseract and the result
+//! is stored next to the image, as plain text sidecar or as searchable PDF.
+//! Tesseract runs as a subprocess, so the sandbox can't be used together
+//! with OCR.
+
+use crate::Config;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+pub const DEFAULT_OCR_COMMAND: &str = "tesseract";
+pub const DEFAULT_OCR_LANGUAGES: &str = "deu+eng";
+
+/// Image types tesseract reads
+const IMAGE_TYPES: [&str; 5] = [
+    "image/png",
+    "image/jpeg",
+    "image/tiff",
+    "image/gif",
+    "image/bmp",
+];
+
+/// Result stored next to the scanned image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OcrOutput {
+    /// Plain text
+    #[default]
+    Text,
+    /// The image as PDF with a text layer
+    Pdf,
+}
+
+/// Runs the configured OCR command
+#[derive(Debug, Clone)]
+pub struct Ocr {
+    command: String,
+    languages: String,
+    output: OcrOutput,
+}
+
+impl Ocr {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            command: config.ocr_command.clone(),
+            languages: config.ocr_languages.clone(),
+            output: config.ocr_output,
+        }
+    }
+
+    /// Returns true for attachments without a text layer
+    pub fn needs_ocr(mimetype: &str) -> bool {
+        IMAGE_TYPES.iter().any(|x| x.eq_ignore_ascii_case(mimetype))
+    }
+
+    /// File name and mimetype of the result of an attachment
+    pub fn sidecar(&self, file_name: &str) -> (String, String) {
+        let stem = Path::new(file_name)
+            .file_stem()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_else(|| file_name.to_owned());
+        match self.output {
+            OcrOutput::Text => (format!("{}.txt", stem), "text/plain".to_owned()),
+            OcrOutput::Pdf => (format!("{}.pdf", stem), "application/pdf".to_owned()),
+        }
+    }
+
+    /// Recognizes the text of the image. The image is passed as temporary
+    /// file, the result is read from stdout.
+    pub fn run(&self, image: &[u8]) -> Result<Vec<u8>> {
+        let mut file = tempfile::NamedTempFile::new().context("Can't create temporary file")?;
+        file.write_all(image)?;
+        file.flush()?;
+        let mut command = Command::new(&self.command);
+        command
+            .arg(file.path())
+            .arg("stdout")
+            .arg("-l")
+            .arg(&self.languages);
+        if self.output == OcrOutput::Pdf {
+            command.arg("pdf");
+        }
+        let output = command
+            .output()
+            .with_context(|| format!("Can't run {}", &self.command))?;
+        if !output.status.success() {
+            bail!(
+                "{} failed with {}: {}",
+                &self.command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(output.stdout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_ocr_command() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("tesseract");
+        std::fs::write(&script, "#!/bin/sh\necho \"$2 $3 $4\"\ncat \"$1\"\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let ocr = Ocr {
+            command: script.to_string_lossy().into_owned(),
+            languages: "deu".to_owned(),
+            output: OcrOutput::Text,
+        };
+        assert_eq!(ocr.run(b"scan").unwrap(), b"stdout -l deu\nscan");
+        assert_eq!(
+            ocr.sidecar("scan.png"),
+            ("scan.txt".to_owned(), "text/plain".to_owned())
+        );
+        assert!(Ocr::needs_ocr("image/PNG"));
+        assert!(!Ocr::needs_ocr("application/pdf"));
+
+        let missing = Ocr {
+            command: dir.path().join("missing").to_string_lossy().into_owned(),
+            ..ocr
+        };
+        assert!(missing.run(b"scan").is_err());
+    }
+}
@@ -0,0 +1,243 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Upload to the REST API of paperless-ngx.
+//!
+//! With `--paperless-url` the attachments are posted to
+//! `api/documents/post_document/` with the API token of `--paperless-token`.
+//! The rendered `paperless_correspondent` and `paperless_tags` templates are
+//! looked up by name and created if they don't exist:
+//!
+//! ```toml
+//! paperless_url = "https://paperless.example.com"
+//! paperless_token = "0123456789abcdef"
+//! paperless_correspondent = "{{ vendor }}"
+//! paperless_tags = ["invoice", "{{ user }}"]
+//! ```
+//!
+//! The title of the document is the file name of the output path without
+//! the extension. paperless-ngx consumes the documents in the background
+//! and refuses duplicates itself.
+
+use crate::attachments::AttachmentBody;
+use crate::pipeline::{AttachmentSink, DocumentInfo};
+use crate::sinks::url_host;
+use crate::Config;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use url::Url;
+
+pub(crate) const CORRESPONDENT_TEMPLATE_NAME: &str = "paperless_correspondent";
+
+/// Names and sources of the templates of the document metadata
+pub(crate) fn templates(config: &Config) -> Vec<(String, &str)> {
+    let mut rv = Vec::new();
+    if let Some(correspondent) = &config.paperless_correspondent {
+        rv.push((
+            CORRESPONDENT_TEMPLATE_NAME.to_owned(),
+            correspondent.as_str(),
+        ));
+    }
+    for (index, tag) in config.paperless_tags.iter().enumerate() {
+        rv.push((format!("paperless_tag_{}", index), tag.as_str()));
+    }
+    rv
+}
+
+/// Page of a list endpoint, only the ids are used
+#[derive(Debug, Deserialize)]
+struct Page {
+    results: Vec<Object>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Object {
+    id: u64,
+}
+
+/// Url of an API endpoint below the paperless-ngx base url
+fn api_url(base: &Url, endpoint: &str) -> Result<Url> {
+    let mut base = base.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    Ok(base.join("api/")?.join(endpoint)?)
+}
+
+/// Stores attachments as documents in paperless-ngx
+#[derive(Debug)]
+pub struct PaperlessSink {
+    url: Url,
+    token: String,
+    client: reqwest::Client,
+    /// Ids of the correspondents and tags by endpoint and lowercase name
+    ids: Mutex<HashMap<(&'static str, String), u64>>,
+}
+
+impl PaperlessSink {
+    pub fn new(url: Url, token: impl Into<String>, client: reqwest::Client) -> Self {
+        Self {
+            url,
+            token: token.into(),
+            client,
+            ids: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, url: Url) -> reqwest::RequestBuilder {
+        self.client.request(method, url).header(
+            reqwest::header::AUTHORIZATION,
+            format!("Token {}", self.token),
+        )
+    }
+
+    /// Returns the id of the correspondent or tag, creates it if missing
+    async fn id_of(&self, endpoint: &'static str, name: &str) -> Result<u64> {
+        let key = (endpoint, name.to_lowercase());
+        let cached = self
+            .ids
+            .lock()
+            .map_err(|_| anyhow::anyhow!("paperless id cache poisoned"))?
+            .get(&key)
+            .copied();
+        if let Some(id) = cached {
+            return Ok(id);
+        }
+        let mut url = api_url(&self.url, endpoint)?;
+        url.query_pairs_mut().append_pair("name__iexact", name);
+        let response = self.request(reqwest::Method::GET, url).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            bail!("Can't look up {} {}: {}", endpoint, name, status);
+        }
+        let page: Page = serde_json::from_slice(&response.bytes().await?)
+            .with_context(|| format!("Invalid response of {}", endpoint))?;
+        let id = match page.results.first() {
+            Some(object) => object.id,
+            None => {
+                log::info!("Creating {} {} in paperless-ngx", endpoint, name);
+                let body = serde_json::to_vec(&serde_json::json!({ "name": name }))?;
+                let response = self
+                    .request(reqwest::Method::POST, api_url(&self.url, endpoint)?)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body)
+                    .send()
+                    .await?;
+                let status = response.status();
+                if !status.is_success() {
+                    bail!("Can't create {} {}: {}", endpoint, name, status);
+                }
+                let object: Object = serde_json::from_slice(&response.bytes().await?)
+                    .with_context(|| format!("Invalid response of {}", endpoint))?;
+                object.id
+            }
+        };
+        if let Ok(mut ids) = self.ids.lock() {
+            ids.insert(key, id);
+        }
+        Ok(id)
+    }
+}
+
+#[async_trait]
+impl AttachmentSink for PaperlessSink {
+    fn name(&self) -> String {
+        format!("paperless:{}", url_host(self.url.as_str()))
+    }
+
+    async fn put(&self, path: &str, _body: Bytes) -> Result<()> {
+        // hash markers and metadata files are kept by the other sinks only
+        log::debug!("Not storing {} in paperless-ngx, it is no attachment", path);
+        Ok(())
+    }
+
+    async fn put_document(
+        &self,
+        path: &str,
+        body: &AttachmentBody,
+        document: &DocumentInfo,
+    ) -> Result<()> {
+        let file_name = path.rsplit('/').next().unwrap_or(path).to_owned();
+        let title = match file_name.rsplit_once('.') {
+            Some((stem, _)) if !stem.is_empty() => stem.to_owned(),
+            _ => file_name.clone(),
+        };
+        let part = match body {
+            AttachmentBody::Memory(bytes) => {
+                reqwest::multipart::Part::stream_with_length(bytes.clone(), bytes.len() as u64)
+            }
+            // streamed from the temporary file instead of loaded into memory
+            AttachmentBody::Spilled(_) => {
+                let file =
+                    tokio::fs::File::open(body.spilled_path().context("No spilled file")?).await?;
+                reqwest::multipart::Part::stream_with_length(
+                    reqwest::Body::wrap_stream(tokio_util::io::ReaderStream::new(file)),
+                    body.len() as u64,
+                )
+            }
+        }
+        .file_name(file_name);
+        let mut form = reqwest::multipart::Form::new()
+            .part("document", part)
+            .text("title", title);
+        if let Some(correspondent) = &document.correspondent {
+            let id = self.id_of("correspondents/", correspondent).await?;
+            form = form.text("correspondent", id.to_string());
+        }
+        for tag in document.tags.iter() {
+            let id = self.id_of("tags/", tag).await?;
+            form = form.text("tags", id.to_string());
+        }
+        let url = api_url(&self.url, "documents/post_document/")?;
+        let response = self
+            .request(reqwest::Method::POST, url)
+            .multipart(form)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!("Upload of {} failed: {}", path, response.status());
+        }
+        Ok(())
+    }
+
+    async fn check(&self) -> Result<()> {
+        let mut url = api_url(&self.url, "documents/")?;
+        url.query_pairs_mut().append_pair("page_size", "1");
+        let response = self
+            .request(reqwest::Method::GET, url)
+            .send()
+            .await
+            .context("Can't reach paperless-ngx")?;
+        if !response.status().is_success() {
+            bail!("Can't list documents: {}", response.status());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_url() {
+        let base = Url::parse("https://example.com/paperless").unwrap();
+        assert_eq!(
+            api_url(&base, "documents/post_document/").unwrap().as_str(),
+            "https://example.com/paperless/api/documents/post_document/"
+        );
+        let base = Url::parse("https://paperless.example.com/").unwrap();
+        assert_eq!(
+            api_url(&base, "tags/").unwrap().as_str(),
+            "https://paperless.example.com/api/tags/"
+        );
+        let page: Page =
+            serde_json::from_str(r#"{"count": 1, "results": [{"id": 7, "name": "ACME"}]}"#)
+                .unwrap();
+        assert_eq!(page.results[0].id, 7);
+    }
+}
@@ -0,0 +1,110 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Passwords of encrypted archives and PDFs.
+//!
+//! Some suppliers send encrypted zip archives or password protected PDFs.
+//! The passwords are declared by sender address or domain in the config
+//! file, the address wins over the domain:
+//!
+//! ```toml
+//! [archive_passwords]
+//! "acme.example" = "s3cret"
+//! "billing@other.example" = "enc:YWdlLWVuY3J5cHRpb24u..."
+//! ```
+//!
+//! Attachments without a known password fail with
+//! [`crate::ErrorCategory::Password`].
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+
+/// Error of an encrypted file without a known password
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordError {
+    /// No password declared for the sender
+    Missing,
+    /// The declared password doesn't decrypt the file
+    Wrong,
+}
+
+impl std::fmt::Display for PasswordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasswordError::Missing => write!(f, "File is encrypted, no password for the sender"),
+            PasswordError::Wrong => write!(f, "Wrong password for the encrypted file"),
+        }
+    }
+}
+
+impl std::error::Error for PasswordError {}
+
+/// Returns true if the error chain contains a [`PasswordError`]
+pub fn is_password_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|x| x.is::<PasswordError>())
+}
+
+/// Password of the sender given by the From header
+pub fn lookup<'a>(passwords: &'a BTreeMap<String, String>, from: &str) -> Option<&'a str> {
+    if passwords.is_empty() {
+        return None;
+    }
+    let addresses = mailparse::addrparse(from).ok()?;
+    let address = addresses.extract_single_info()?.addr.to_lowercase();
+    let domain = address.rsplit_once('@').map(|(_, x)| x.to_owned());
+    let find = |key: &str| {
+        passwords
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(key))
+            .map(|(_, password)| password.as_str())
+    };
+    find(&address).or_else(|| domain.and_then(|x| find(&x)))
+}
+
+/// Returns true if the PDF has an encryption dictionary
+pub fn is_encrypted_pdf(body: &[u8]) -> bool {
+    body.starts_with(b"%PDF-") && body.windows(8).any(|x| x == b"/Encrypt")
+}
+
+/// Returns the decrypted PDF. PDFs that only restrict the permissions
+/// open with an empty password and are returned unchanged.
+pub fn decrypt_pdf(body: &[u8], password: Option<&str>) -> Result<Option<Vec<u8>>> {
+    let mut document = lopdf::Document::load_mem(body).context("Can't parse PDF")?;
+    if !document.is_encrypted() || document.clone().decrypt("").is_ok() {
+        return Ok(None);
+    }
+    let password = password.ok_or(PasswordError::Missing)?;
+    document
+        .decrypt(password)
+        .map_err(|_| PasswordError::Wrong)?;
+    let mut rv = Vec::new();
+    document
+        .save_to(&mut rv)
+        .context("Can't write decrypted PDF")?;
+    Ok(Some(rv))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let passwords: BTreeMap<String, String> = [
+            ("acme.example", "domain"),
+            ("Billing@acme.example", "address"),
+        ]
+        .into_iter()
+        .map(|(x, y)| (x.to_owned(), y.to_owned()))
+        .collect();
+        let password = |from| lookup(&passwords, from);
+        assert_eq!(password("ACME <billing@ACME.example>"), Some("address"));
+        assert_eq!(password("sales@acme.example"), Some("domain"));
+        assert_eq!(password("sales@other.example"), None);
+        assert_eq!(password("unknown"), None);
+
+        let err = anyhow::Error::new(PasswordError::Missing).context("Can't unpack invoice.zip");
+        assert!(is_password_error(&err));
+        assert!(!is_password_error(&anyhow::anyhow!("broken")));
+    }
+}
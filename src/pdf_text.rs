@@ -0,0 +1,132 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Invoice data read from the text of PDFs.
+//!
+//! With `--pdf-text` the text of PDF attachments is extracted and searched
+//! with configurable regular expressions. The first capture group of each
+//! pattern is the value:
+//!
+//! ```toml
+//! pdf_text = true
+//! invoice_no_pattern = '(?i)beleg-nr\.?:?\s*(\S+)'
+//! ```
+//!
+//! Found values fill the fields missing in the structured invoice data, see
+//! [`crate::invoice`], and are available as `invoice_no`, `invoice_date` and
+//! `amount` in the output template.
+
+use crate::invoice::InvoiceData;
+use crate::Config;
+use anyhow::{Context, Result};
+use regex::Regex;
+
+/// Pages after this one are not searched
+pub const MAX_PAGES: u32 = 20;
+
+/// Invoice number after a German or English label
+pub const DEFAULT_INVOICE_NO_PATTERN: &str =
+    r"(?i)(?:rechnungs-?(?:nr|nummer)|invoice\s*(?:no|number|#))\.?\s*:?\s*([A-Z0-9][A-Z0-9/_.-]*)";
+
+/// Issue date as DD.MM.YYYY or YYYY-MM-DD after a label
+pub const DEFAULT_INVOICE_DATE_PATTERN: &str = r"(?i)(?:rechnungsdatum|invoice\s*date|datum|date)\s*:?\s*(\d{1,2}\.\d{1,2}\.\d{4}|\d{4}-\d{2}-\d{2})";
+
+/// Total after a label, with decimal point or comma
+pub const DEFAULT_AMOUNT_PATTERN: &str = r"(?i)(?:gesamtbetrag|rechnungsbetrag|endbetrag|total)[^\d\n]{0,30}(\d{1,3}(?:[.,' ]?\d{3})*[.,]\d{2})";
+
+/// Date formats of the invoice date, normalized to YYYY-MM-DD
+const DATE_FORMATS: [&str; 3] = ["%d.%m.%Y", "%Y-%m-%d", "%d/%m/%Y"];
+
+/// Compiled patterns of the config
+#[derive(Debug, Clone)]
+pub struct TextPatterns {
+    invoice_no: Regex,
+    invoice_date: Regex,
+    amount: Regex,
+}
+
+impl TextPatterns {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let compile = |name: &str, pattern: &str| {
+            Regex::new(pattern).with_context(|| format!("Invalid {}", name))
+        };
+        Ok(Self {
+            invoice_no: compile("invoice_no_pattern", &config.invoice_no_pattern)?,
+            invoice_date: compile("invoice_date_pattern", &config.invoice_date_pattern)?,
+            amount: compile("amount_pattern", &config.amount_pattern)?,
+        })
+    }
+
+    /// Searches the text for the invoice data
+    pub fn parse(&self, text: &str) -> InvoiceData {
+        let capture = |pattern: &Regex| {
+            pattern
+                .captures(text)
+                .and_then(|x| x.get(1))
+                .map(|x| x.as_str().trim().to_owned())
+        };
+        InvoiceData {
+            number: capture(&self.invoice_no),
+            date: capture(&self.invoice_date).and_then(|x| parse_date(&x)),
+            amount: capture(&self.amount).and_then(|x| parse_amount(&x)),
+            ..Default::default()
+        }
+    }
+
+    /// Extracts the text of the PDF and searches it
+    pub fn extract(&self, body: &[u8]) -> Result<InvoiceData> {
+        Ok(self.parse(&extract_text(body)?))
+    }
+}
+
+/// Text of the first [`MAX_PAGES`] pages
+pub fn extract_text(body: &[u8]) -> Result<String> {
+    let document = lopdf::Document::load_mem(body).context("Can't parse PDF")?;
+    let pages: Vec<u32> = document
+        .get_pages()
+        .into_keys()
+        .filter(|x| *x <= MAX_PAGES)
+        .collect();
+    document
+        .extract_text(&pages)
+        .context("Can't extract text of PDF")
+}
+
+fn parse_date(text: &str) -> Option<String> {
+    DATE_FORMATS
+        .iter()
+        .find_map(|format| chrono::NaiveDate::parse_from_str(text, format).ok())
+        .map(|x| x.format("%Y-%m-%d").to_string())
+}
+
+/// Parses `1.234,56`, `1,234.56` and `1'234.56`, the last separator is the
+/// decimal one
+fn parse_amount(text: &str) -> Option<f64> {
+    let decimal = text.rfind(|x| x == '.' || x == ',')?;
+    let (integer, fraction) = text.split_at(decimal);
+    let integer: String = integer.chars().filter(|x| x.is_ascii_digit()).collect();
+    format!("{}.{}", integer, &fraction[1..]).parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_text() {
+        let patterns = TextPatterns::from_config(&Config::default()).unwrap();
+        let text = "ACME GmbH\nRechnungsnummer: RE-2023/0815\nRechnungsdatum: 15.03.2023\n\
+            Pos. 1 Beratung 1.000,00 EUR\nGesamtbetrag (brutto): 1.190,00 EUR\n";
+        let data = patterns.parse(text);
+        assert_eq!(data.number.as_deref(), Some("RE-2023/0815"));
+        assert_eq!(data.date.as_deref(), Some("2023-03-15"));
+        assert_eq!(data.amount, Some(1190.0));
+
+        let data = patterns.parse("Invoice No. 4711\nInvoice date 2023-01-31\nTotal: $1,234.50");
+        assert_eq!(data.number.as_deref(), Some("4711"));
+        assert_eq!(data.date.as_deref(), Some("2023-01-31"));
+        assert_eq!(data.amount, Some(1234.5));
+
+        assert!(patterns.parse("Dear customer").is_empty());
+    }
+}
@@ -0,0 +1,231 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! WASM plugin host.
+//!
+//! Plugins are WebAssembly modules without imports, for example build with
+//! `cargo build --target wasm32-unknown-unknown`. They are loaded with
+//! `--wasm-plugins` and run as a [`ProcessingHook`].
+//!
+//! ABI version 1, all exports except `memory`, `i2s_abi_version` and
+//! `i2s_alloc` are optional:
+//!
+//! | export                                     | description                                  |
+//! |--------------------------------------------|----------------------------------------------|
+//! | `memory`                                   | linear memory of the plugin                  |
+//! | `i2s_abi_version() -> i32`                 | must return `1`                              |
+//! | `i2s_alloc(len: i32) -> i32`               | allocates `len` bytes for the input          |
+//! | `i2s_extract_user(ptr, len) -> i64`        | input: message json, output: user name       |
+//! | `i2s_route_mail(ptr, len) -> i64`          | input: mail json, output: mail json          |
+//! | `i2s_route_attachment(ptr, len) -> i64`    | input: attachment json, output: path         |
+//! | `i2s_transform_attachment(ptr, len) -> i64`| input: attachment body, output: new body     |
+//!
+//! Outputs are returned as `(ptr << 32) | len` pointing into the plugin memory.
+//! Returning `0` means the plugin keeps the current value.
+
+use crate::hooks::{Attachment, ProcessingHook};
+use anyhow::{anyhow, bail, Context, Result};
+use mailparse::{MailHeaderMap, ParsedMail};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use wasmtime::{Engine, Instance, Linker, Memory, Module, Store, TypedFunc};
+
+/// ABI version plugins have to implement
+pub const PLUGIN_ABI_VERSION: i32 = 1;
+/// Fuel each plugin call may consume, protects against endless loops
+const PLUGIN_FUEL: u64 = 1_000_000_000;
+
+/// Message information passed to `i2s_extract_user`
+#[derive(Serialize)]
+struct MessageInput<'a> {
+    from: Option<String>,
+    to: Option<String>,
+    subject: Option<String>,
+    user: &'a Option<String>,
+}
+
+/// Mail target passed to and returned from `i2s_route_mail`
+#[derive(Serialize, Deserialize)]
+struct MailRoute {
+    target: String,
+    flags: Vec<String>,
+}
+
+/// Attachment information passed to `i2s_route_attachment`
+#[derive(Serialize)]
+struct AttachmentInput<'a> {
+    file_name: &'a str,
+    mimetype: &'a str,
+    size: usize,
+    path: &'a str,
+}
+
+struct PluginInstance {
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    extract_user: Option<TypedFunc<(i32, i32), i64>>,
+    route_mail: Option<TypedFunc<(i32, i32), i64>>,
+    route_attachment: Option<TypedFunc<(i32, i32), i64>>,
+    transform_attachment: Option<TypedFunc<(i32, i32), i64>>,
+}
+
+impl PluginInstance {
+    /// Copies the input into the plugin memory, calls the function and
+    /// returns the output. `None` if the plugin returned 0.
+    fn call(&mut self, func: TypedFunc<(i32, i32), i64>, input: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.store.set_fuel(PLUGIN_FUEL)?;
+        let len = i32::try_from(input.len()).context("Plugin input too large")?;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory
+            .write(&mut self.store, ptr as usize, input)
+            .context("Plugin allocated invalid memory")?;
+        let packed = func.call(&mut self.store, (ptr, len))?;
+        if packed == 0 {
+            return Ok(None);
+        }
+        let out_ptr = (packed as u64 >> 32) as usize;
+        let out_len = (packed as u64 & 0xffff_ffff) as usize;
+        let mut output = vec![0; out_len];
+        self.memory
+            .read(&self.store, out_ptr, &mut output)
+            .context("Plugin returned invalid memory")?;
+        Ok(Some(output))
+    }
+}
+
+/// A loaded WASM plugin
+pub struct WasmPlugin {
+    path: PathBuf,
+    instance: Mutex<PluginInstance>,
+}
+
+impl WasmPlugin {
+    /// Loads and instantiates the plugin module
+    pub fn load(engine: &Engine, path: &Path) -> Result<Self> {
+        let module = Module::from_file(engine, path)
+            .with_context(|| format!("Can't load plugin: {}", path.display()))?;
+        let mut store = Store::new(engine, ());
+        store.set_fuel(PLUGIN_FUEL)?;
+        let linker: Linker<()> = Linker::new(engine);
+        let instance: Instance = linker.instantiate(&mut store, &module)?;
+
+        let version = instance
+            .get_typed_func::<(), i32>(&mut store, "i2s_abi_version")?
+            .call(&mut store, ())?;
+        if version != PLUGIN_ABI_VERSION {
+            bail!(
+                "Plugin {} uses ABI version {}, supported is {}",
+                path.display(),
+                version,
+                PLUGIN_ABI_VERSION
+            );
+        }
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow!("Plugin {} does not export memory", path.display()))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "i2s_alloc")?;
+        let extract_user = instance.get_typed_func(&mut store, "i2s_extract_user").ok();
+        let route_mail = instance.get_typed_func(&mut store, "i2s_route_mail").ok();
+        let route_attachment = instance
+            .get_typed_func(&mut store, "i2s_route_attachment")
+            .ok();
+        let transform_attachment = instance
+            .get_typed_func(&mut store, "i2s_transform_attachment")
+            .ok();
+        log::info!("Loaded plugin: {}", path.display());
+
+        Ok(Self {
+            path: path.to_owned(),
+            instance: Mutex::new(PluginInstance {
+                store,
+                memory,
+                alloc,
+                extract_user,
+                route_mail,
+                route_attachment,
+                transform_attachment,
+            }),
+        })
+    }
+
+    fn call(
+        &self,
+        select: impl Fn(&PluginInstance) -> Option<TypedFunc<(i32, i32), i64>>,
+        input: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        let mut instance = self
+            .instance
+            .lock()
+            .map_err(|_| anyhow!("Plugin {} poisoned", self.path.display()))?;
+        match select(&*instance) {
+            Some(func) => instance
+                .call(func, input)
+                .with_context(|| format!("Plugin {} failed", self.path.display())),
+            None => Ok(None),
+        }
+    }
+}
+
+impl ProcessingHook for WasmPlugin {
+    fn user_resolved(&self, message: &ParsedMail, user: &mut Option<String>) -> Result<()> {
+        let input = serde_json::to_vec(&MessageInput {
+            from: message.headers.get_first_value("from"),
+            to: message.headers.get_first_value("to"),
+            subject: message.headers.get_first_value("subject"),
+            user,
+        })?;
+        if let Some(output) = self.call(|x| x.extract_user.clone(), &input)? {
+            let new_user = String::from_utf8(output).context("Plugin returned invalid user")?;
+            log::debug!("Plugin {} set user: {}", self.path.display(), &new_user);
+            *user = Some(new_user);
+        }
+        Ok(())
+    }
+
+    fn transform_attachment(&self, attachment: &Attachment) -> Result<Option<Vec<u8>>> {
+        self.call(|x| x.transform_attachment.clone(), attachment.body)
+    }
+
+    fn before_upload(&self, path: &mut String, attachment: &Attachment) -> Result<()> {
+        let input = serde_json::to_vec(&AttachmentInput {
+            file_name: attachment.file_name,
+            mimetype: attachment.mimetype,
+            size: attachment.body.len(),
+            path,
+        })?;
+        if let Some(output) = self.call(|x| x.route_attachment.clone(), &input)? {
+            *path = String::from_utf8(output).context("Plugin returned invalid path")?;
+        }
+        Ok(())
+    }
+
+    fn before_mail_store(&self, target: &mut String, flags: &mut Vec<String>) -> Result<()> {
+        let input = serde_json::to_vec(&MailRoute {
+            target: target.clone(),
+            flags: flags.clone(),
+        })?;
+        if let Some(output) = self.call(|x| x.route_mail.clone(), &input)? {
+            let route: MailRoute =
+                serde_json::from_slice(&output).context("Plugin returned invalid route")?;
+            *target = route.target;
+            *flags = route.flags;
+        }
+        Ok(())
+    }
+}
+
+/// Loads all plugins with a shared engine
+pub fn load_plugins(paths: &[PathBuf]) -> Result<Vec<WasmPlugin>> {
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut config = wasmtime::Config::new();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config)?;
+    paths
+        .iter()
+        .map(|path| WasmPlugin::load(&engine, path))
+        .collect()
+}
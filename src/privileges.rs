@@ -0,0 +1,154 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Drops root privileges of the daemon modes. Started as root by systemd,
+//! sockets and log files are opened first, then the process continues as
+//! the configured user and group.
+
+use anyhow::{bail, Context, Result};
+use std::ffi::CString;
+
+/// Umask of the daemon modes if none is configured, stored files and
+/// folders are not readable by other users
+pub const DEFAULT_DAEMON_UMASK: u32 = 0o027;
+
+/// Parses an octal umask like `027`
+pub fn parse_umask(text: &str) -> Result<u32> {
+    let umask = u32::from_str_radix(text.trim(), 8)
+        .with_context(|| format!("Invalid umask {:?}, expected octal like 027", text))?;
+    if umask > 0o777 {
+        bail!("Invalid umask {:?}", text);
+    }
+    Ok(umask)
+}
+
+/// Sets the umask of the process
+pub fn set_umask(umask: u32) {
+    // SAFETY: umask can't fail
+    unsafe {
+        libc::umask(umask as libc::mode_t);
+    }
+}
+
+/// Looks up the uid and primary gid of a user name or numeric id
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = CString::new(name)?;
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    // SAFETY: buffers are valid for the duration of the call
+    let rc = unsafe {
+        if let Ok(uid) = name.parse::<libc::uid_t>() {
+            libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+        } else {
+            libc::getpwnam_r(
+                c_name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        }
+    };
+    if rc != 0 || result.is_null() {
+        bail!("Unknown user {}", name);
+    }
+    Ok((pwd.pw_uid, pwd.pw_gid))
+}
+
+/// Looks up the gid of a group name or numeric id
+fn lookup_group(name: &str) -> Result<libc::gid_t> {
+    if let Ok(gid) = name.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+    let c_name = CString::new(name)?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result: *mut libc::group = std::ptr::null_mut();
+    // SAFETY: buffers are valid for the duration of the call
+    let rc = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if rc != 0 || result.is_null() {
+        bail!("Unknown group {}", name);
+    }
+    Ok(grp.gr_gid)
+}
+
+/// Switches to the user and group. The group defaults to the primary
+/// group of the user. Does nothing if the process doesn't run as root.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    if user.is_none() && group.is_none() {
+        return Ok(());
+    }
+    // SAFETY: geteuid can't fail
+    if unsafe { libc::geteuid() } != 0 {
+        log::warn!("Not running as root, user and group are not changed");
+        return Ok(());
+    }
+    let (uid, user_gid) = match user {
+        Some(user) => {
+            let (uid, gid) = lookup_user(user)?;
+            (Some(uid), Some(gid))
+        }
+        None => (None, None),
+    };
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => user_gid,
+    };
+
+    // SAFETY: plain syscalls, the results are checked
+    unsafe {
+        if let Some(gid) = gid {
+            if libc::setgroups(1, &gid) != 0 {
+                bail!("setgroups failed: {}", std::io::Error::last_os_error());
+            }
+            if libc::setgid(gid) != 0 {
+                bail!("setgid {} failed: {}", gid, std::io::Error::last_os_error());
+            }
+        }
+        if let Some(uid) = uid {
+            if libc::setuid(uid) != 0 {
+                bail!("setuid {} failed: {}", uid, std::io::Error::last_os_error());
+            }
+            // make sure root can't be regained
+            if uid != 0 && libc::setuid(0) == 0 {
+                bail!("Privileges could not be dropped");
+            }
+        }
+    }
+    log::info!(
+        "Running as uid {} gid {}",
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() }
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_umask() {
+        assert_eq!(parse_umask("027").unwrap(), 0o027);
+        assert_eq!(parse_umask("0077").unwrap(), 0o077);
+        assert!(parse_umask("999").is_err());
+        assert!(parse_umask("1777").is_err());
+    }
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup_user("root").unwrap().0, 0);
+        assert_eq!(lookup_user("0").unwrap().0, 0);
+        assert_eq!(lookup_group("0").unwrap(), 0);
+        assert!(lookup_user("no-such-user-invoice2storage").is_err());
+    }
+}
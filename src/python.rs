@@ -0,0 +1,73 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Python bindings, build with `maturin build --features python`.
+//!
+//! ```python
+//! import invoice2storage
+//!
+//! with open("mail.eml", "rb") as f:
+//!     result = invoice2storage.process_bytes({"local_path": "/srv/invoices"}, f.read())
+//! print(result["success"], result["files"])
+//! ```
+
+use crate::{Config, Processor};
+use clap_serde_derive::ClapSerde;
+use lazy_static::lazy_static;
+use pyo3::exceptions::{PyRuntimeError, PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyString};
+
+lazy_static! {
+    /// Shared by all calls, pooled http connections are bound to the
+    /// runtime they were created in
+    static ref RUNTIME: std::io::Result<tokio::runtime::Runtime> =
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build();
+}
+
+/// Parses the config from a toml string or a dict with the keys of the
+/// config file
+fn parse_config(py: Python, config: &PyAny) -> PyResult<Config> {
+    let invalid =
+        |err: &dyn std::fmt::Display| PyValueError::new_err(format!("Invalid config: {}", err));
+    let opt: <Config as ClapSerde>::Opt = if let Ok(text) = config.downcast::<PyString>() {
+        toml::from_str(text.to_str()?).map_err(|err| invalid(&err))?
+    } else if config.downcast::<PyDict>().is_ok() {
+        let json: String = py
+            .import("json")?
+            .call_method1("dumps", (config,))?
+            .extract()?;
+        serde_json::from_str(&json).map_err(|err| invalid(&err))?
+    } else {
+        return Err(PyTypeError::new_err("config must be a toml string or dict"));
+    };
+    let config = Config::from(opt);
+    config
+        .validate()
+        .map_err(|err| invalid(&format!("{:#}", err)))?;
+    Ok(config)
+}
+
+/// Processes the raw mail and returns the result as dict
+#[pyfunction]
+fn process_bytes(py: Python, config: &PyAny, raw_mail: &[u8]) -> PyResult<PyObject> {
+    let config = parse_config(py, config)?;
+    let processor = Processor::from_config(config)
+        .map_err(|err| PyValueError::new_err(format!("{:#}", err)))?;
+    let runtime = RUNTIME
+        .as_ref()
+        .map_err(|err| PyRuntimeError::new_err(err.to_string()))?;
+    let result = py.allow_threads(|| runtime.block_on(processor.process(raw_mail)));
+    let json = result
+        .to_json()
+        .map_err(|err| PyRuntimeError::new_err(err.to_string()))?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?.into())
+}
+
+#[pymodule]
+fn invoice2storage(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(process_bytes, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,137 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Quarantine of unparsable and rejected messages.
+//!
+//! With `--quarantine-path` messages that can't be parsed as mime mail, or
+//! that a hook rejects after parsing, are kept verbatim for a later look.
+//! Each message is written as `<time>-<hash>.eml` next to a
+//! `<time>-<hash>.reason.txt` with the reason and the errors of the run.
+//!
+//! An absolute path is a local directory. A relative path is a prefix in
+//! the storage backends, like the rendered `output_template`:
+//!
+//! ```toml
+//! quarantine_path = "quarantine"
+//! ```
+//!
+//! The processing of the message is not changed, it still gets the error
+//! flags.
+
+use crate::ProcessResult;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// Hex digits of the content hash in the file names
+const HASH_LENGTH: usize = 12;
+
+/// Where quarantined messages are written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Quarantine {
+    /// Local directory
+    Local(PathBuf),
+    /// Prefix in the storage backends
+    Backend(String),
+}
+
+impl Quarantine {
+    /// Absolute paths are local directories, relative ones backend prefixes
+    pub fn from_path(path: &Path) -> Self {
+        if path.is_absolute() {
+            Quarantine::Local(path.to_owned())
+        } else {
+            let prefix = path.to_string_lossy().trim_matches('/').to_owned();
+            Quarantine::Backend(prefix)
+        }
+    }
+
+    /// Paths of the message and the reason file
+    pub fn paths(&self, name: &str) -> (String, String) {
+        let base = match self {
+            Quarantine::Local(dir) => dir.join(name).to_string_lossy().into_owned(),
+            Quarantine::Backend(prefix) if prefix.is_empty() => name.to_owned(),
+            Quarantine::Backend(prefix) => format!("{}/{}", prefix, name),
+        };
+        (format!("{}.eml", base), format!("{}.reason.txt", base))
+    }
+
+    /// Writes the message and the reason file to the local directory.
+    /// Returns the path of the message.
+    pub fn write_local(&self, name: &str, content: &[u8], reason: &str) -> Result<String> {
+        if let Quarantine::Local(dir) = self {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Can't create quarantine {}", dir.display()))?;
+        }
+        let (mail, reason_file) = self.paths(name);
+        std::fs::write(&mail, content).with_context(|| format!("Can't write {}", mail))?;
+        std::fs::write(&reason_file, reason)
+            .with_context(|| format!("Can't write {}", reason_file))?;
+        Ok(mail)
+    }
+}
+
+/// File name of the message without extension, the time keeps them in
+/// order and the hash apart
+pub fn entry_name(content: &[u8], time: &chrono::DateTime<chrono::Local>) -> String {
+    let hash = format!("{:x}", Sha256::digest(content));
+    format!("{}-{}", time.format("%Y%m%dT%H%M%S"), &hash[..HASH_LENGTH])
+}
+
+/// Content of the reason file
+pub fn reason_text(
+    reason: &str,
+    result: &ProcessResult,
+    time: &chrono::DateTime<chrono::Local>,
+) -> String {
+    let mut text = String::new();
+    let _ = writeln!(text, "reason: {}", reason);
+    let _ = writeln!(text, "time: {}", time.to_rfc3339());
+    if let Some(message_id) = &result.message_id {
+        let _ = writeln!(text, "message-id: {}", message_id);
+    }
+    for error in result.errors.iter() {
+        let _ = writeln!(text, "error: {:?}: {}", error.category, error.message);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorCategory;
+
+    #[test]
+    fn test_quarantine() {
+        assert_eq!(
+            Quarantine::from_path(Path::new("/var/quarantine")),
+            Quarantine::Local("/var/quarantine".into())
+        );
+        let backend = Quarantine::from_path(Path::new("quarantine/"));
+        assert_eq!(
+            backend.paths("a"),
+            (
+                "quarantine/a.eml".to_owned(),
+                "quarantine/a.reason.txt".to_owned()
+            )
+        );
+
+        let time =
+            chrono::TimeZone::with_ymd_and_hms(&chrono::Local, 2023, 5, 1, 12, 0, 0).unwrap();
+        let name = entry_name(b"garbage", &time);
+        assert!(name.starts_with("20230501T120000-"));
+        assert_eq!(name.len(), "20230501T120000-".len() + HASH_LENGTH);
+
+        let mut result = ProcessResult::default();
+        result.add_error(ErrorCategory::Parse, "no header");
+        let text = reason_text("Can't parse mime mail", &result, &time);
+        assert!(text.starts_with("reason: Can't parse mime mail\ntime: 2023-05-01T12:00:00"));
+        assert!(text.ends_with("error: Parse: no header\n"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let local = Quarantine::from_path(&dir.path().join("quarantine"));
+        let mail = local.write_local(&name, b"garbage", &text).unwrap();
+        assert_eq!(std::fs::read(mail).unwrap(), b"garbage");
+    }
+}
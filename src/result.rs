@@ -0,0 +1,422 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Result of processing a message
+
+use crate::redact::redact_credentials;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::time::Duration;
+
+/// Step of the processing an error occurred in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// Reading the message failed
+    Input,
+    /// The message is not a valid mime mail
+    Parse,
+    /// A processing hook returned an error
+    Hook,
+    /// The user could not be looked up in the directory
+    Directory,
+    /// A template could not be rendered
+    Template,
+    /// An attachment could not be decoded
+    Attachment,
+    /// An encrypted archive or PDF has no or a wrong password
+    Password,
+    /// Uploading to the storage backend failed
+    Storage,
+    /// Storing the mail in the maildir or imap target failed
+    MailStore,
+    /// Writing the mail to stdout failed
+    Output,
+    /// The processing was cancelled, for example on shutdown
+    Cancelled,
+    /// No attachment matched, with `--on-no-attachment error`
+    NoAttachment,
+}
+
+/// Name of the category like in the json results
+impl Display for ErrorCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(name)) => f.write_str(&name),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// Error that occurred while processing
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessError {
+    pub category: ErrorCategory,
+    pub message: String,
+    /// Original file name of the attachment the error belongs to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<String>,
+    /// Name of the storage backend that failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+}
+
+impl ProcessError {
+    pub fn new(category: ErrorCategory, message: impl Into<String>) -> Self {
+        Self {
+            category,
+            message: message.into(),
+            attachment: None,
+            backend: None,
+        }
+    }
+
+    pub fn with_attachment(mut self, file_name: impl Into<String>) -> Self {
+        self.attachment = Some(file_name.into());
+        self
+    }
+
+    pub fn with_backend(mut self, backend: impl Into<String>) -> Self {
+        self.backend = Some(backend.into());
+        self
+    }
+}
+
+/// Outcome of an attachment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    /// Stored in all backends
+    #[default]
+    Stored,
+    /// Rejected by a hook
+    Skipped,
+    /// Not stored in at least one backend, see `error`
+    Failed,
+    /// Stored before, see [`crate::dedupe`]
+    Duplicate,
+}
+
+/// An attachment of the message
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FileResult {
+    /// Original file name of the attachment
+    pub file_name: String,
+    pub mimetype: String,
+    /// Rendered output path on the storage backend, empty if the path
+    /// could not be rendered
+    pub path: String,
+    /// Size in bytes
+    pub size: usize,
+    /// Hex encoded SHA-256 of the content
+    pub sha256: String,
+    /// Name of the archive the file was unpacked from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive: Option<String>,
+    /// Backends the attachment was stored in
+    pub backends: Vec<String>,
+    /// Backends storing the attachment failed in
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failed_backends: Vec<String>,
+    /// Fallback backend holding the attachment instead of the failed ones
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<String>,
+    pub status: FileStatus,
+    /// Kind of the first error of a failed attachment
+    pub error: Option<ErrorCategory>,
+}
+
+/// Attachments of the message stored and failed in one backend
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BackendResult {
+    pub name: String,
+    pub stored: usize,
+    pub failed: usize,
+}
+
+/// Time spent in the processing steps in milliseconds
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Timings {
+    pub parse_ms: u64,
+    pub extract_ms: u64,
+    pub store_ms: u64,
+    pub total_ms: u64,
+}
+
+impl Timings {
+    pub(crate) fn millis(duration: Duration) -> u64 {
+        duration.as_millis().try_into().unwrap_or(u64::MAX)
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ProcessResult {
+    /// Id of the message given by the source, like the file name
+    pub message: Option<String>,
+    /// `Message-ID` header without the angle brackets
+    pub message_id: Option<String>,
+    /// Recorded as successful in the index before and not processed again,
+    /// see [`crate::index`]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub already_processed: bool,
+    /// Set when the processing finished, see [`ProcessResult::is_success`]
+    pub success: bool,
+    pub num_errors: u32,
+    /// Rendered paths of all stored files
+    pub files: Vec<String>,
+    /// Stored, skipped and failed attachments
+    pub attachments: Vec<FileResult>,
+    /// Attachments by backend, in the order the backends were used
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub backends: Vec<BackendResult>,
+    /// Errors of all steps, see [`ProcessError::category`]
+    pub errors: Vec<ProcessError>,
+    pub user: Option<String>,
+    /// Tenant the message was processed for, see [`crate::tenants`]
+    pub tenant: Option<String>,
+    /// Canonical vendor of the sender, see [`crate::vendor`]
+    pub vendor: Option<String>,
+    /// Names of the matched routing rules, see [`crate::rules`]
+    pub rules: Vec<String>,
+    pub mailbox: Option<String>,
+    /// Names of the mail sinks the message was stored in
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mail_sinks: Vec<String>,
+    /// Reason the attachments were ignored, see [`crate::skip`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+    /// Path the message was kept at, see [`crate::quarantine`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quarantined: Option<String>,
+    /// Path the original message was stored at with `--store-eml`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eml: Option<String>,
+    /// Flags of the stored mail
+    pub flags: Vec<String>,
+    pub timings: Timings,
+}
+
+impl ProcessResult {
+    pub fn is_success(&self) -> bool {
+        self.num_errors == 0
+    }
+
+    /// Records an error and counts it. Credentials in urls are removed.
+    pub fn add_error(&mut self, category: ErrorCategory, message: impl Into<String>) {
+        self.push_error(ProcessError::new(category, message));
+    }
+
+    /// Records an error with its details and counts it. Credentials in
+    /// urls are removed.
+    pub fn push_error(&mut self, mut error: ProcessError) {
+        self.num_errors += 1;
+        error.message = redact_credentials(&error.message);
+        self.errors.push(error);
+    }
+
+    /// Records an attachment, stored ones are added to the files
+    pub fn add_file(&mut self, file: FileResult) {
+        if file.status == FileStatus::Stored {
+            self.files.push(file.path.clone());
+        }
+        for name in file.backends.iter() {
+            self.backend(name).stored += 1;
+        }
+        for name in file.failed_backends.iter() {
+            self.backend(name).failed += 1;
+        }
+        self.attachments.push(file);
+    }
+
+    fn backend(&mut self, name: &str) -> &mut BackendResult {
+        let index = match self.backends.iter().position(|x| x.name == name) {
+            Some(x) => x,
+            None => {
+                self.backends.push(BackendResult {
+                    name: name.to_owned(),
+                    ..Default::default()
+                });
+                self.backends.len() - 1
+            }
+        };
+        &mut self.backends[index]
+    }
+
+    /// Records an attachment that failed before the upload with its error
+    pub fn add_failed_file(
+        &mut self,
+        file_name: &str,
+        mimetype: &str,
+        category: ErrorCategory,
+        message: impl Into<String>,
+    ) {
+        self.push_error(ProcessError::new(category, message).with_attachment(file_name));
+        self.add_file(FileResult {
+            file_name: file_name.to_owned(),
+            mimetype: mimetype.to_owned(),
+            status: FileStatus::Failed,
+            error: Some(category),
+            ..Default::default()
+        });
+    }
+
+    /// Number of errors of a step
+    pub fn error_count(&self, category: ErrorCategory) -> usize {
+        self.errors
+            .iter()
+            .filter(|x| x.category == category)
+            .count()
+    }
+
+    /// Serializes the result as json
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+}
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// Renders a table with one row per message for reviewing batch runs
+pub fn summary_table(results: &[ProcessResult], color: bool) -> String {
+    let header = ["message", "user", "files", "destination", "status"];
+    let rows: Vec<[String; 5]> = results
+        .iter()
+        .map(|result| {
+            let status = match result.errors.first() {
+                None => "ok".to_owned(),
+                Some(err) => format!("failed: {}", err.category),
+            };
+            [
+                result.message.clone().unwrap_or_else(|| "-".to_owned()),
+                result.user.clone().unwrap_or_else(|| "-".to_owned()),
+                result.files.len().to_string(),
+                result.mailbox.clone().unwrap_or_else(|| "-".to_owned()),
+                status,
+            ]
+        })
+        .collect();
+
+    let mut widths = header.map(|x| x.chars().count());
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let format_row = |cells: &[String]| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        line.join("  ").trim_end().to_owned()
+    };
+
+    let mut table = String::new();
+    table.push_str(&format_row(&header.map(|x| x.to_owned())));
+    table.push('\n');
+    for (row, result) in rows.iter().zip(results.iter()) {
+        let line = format_row(row);
+        match (color, result.is_success()) {
+            (false, _) => table.push_str(&line),
+            (true, true) => table.push_str(&format!("{}{}{}", GREEN, line, RESET)),
+            (true, false) => table.push_str(&format!("{}{}{}", RED, line, RESET)),
+        }
+        table.push('\n');
+    }
+    let failed = results.iter().filter(|x| !x.is_success()).count();
+    let files: usize = results.iter().map(|x| x.files.len()).sum();
+    table.push_str(&format!(
+        "{} messages, {} files stored, {} failed\n",
+        results.len(),
+        files,
+        failed
+    ));
+    table
+}
+
+impl Display for ProcessResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Process result: {}. {} files processed, user: {}, mailbox: {}",
+            if self.is_success() {
+                "success"
+            } else {
+                "failure"
+            },
+            self.files.len(),
+            self.user.clone().unwrap_or("[unknown]".into()),
+            self.mailbox.clone().unwrap_or("[unknown]".into())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backend_results() {
+        let mut result = ProcessResult::default();
+        result.add_file(FileResult {
+            backends: vec!["local".to_owned(), "webdav".to_owned()],
+            ..Default::default()
+        });
+        result.add_file(FileResult {
+            backends: vec!["local".to_owned()],
+            failed_backends: vec!["webdav".to_owned()],
+            status: FileStatus::Failed,
+            ..Default::default()
+        });
+        let backend = |name: &str, stored, failed| BackendResult {
+            name: name.to_owned(),
+            stored,
+            failed,
+        };
+        assert_eq!(
+            result.backends,
+            vec![backend("local", 2, 0), backend("webdav", 1, 1)]
+        );
+    }
+
+    #[test]
+    fn test_summary_table() {
+        let mut ok = ProcessResult {
+            message: Some("a.eml".to_owned()),
+            user: Some("alice".to_owned()),
+            mailbox: Some("alice.done".to_owned()),
+            ..Default::default()
+        };
+        ok.add_file(FileResult {
+            file_name: "invoice.pdf".to_owned(),
+            mimetype: "application/pdf".to_owned(),
+            path: "alice/invoice.pdf".to_owned(),
+            size: 10,
+            ..Default::default()
+        });
+        let mut failed = ProcessResult {
+            message: Some("longer-name.eml".to_owned()),
+            ..Default::default()
+        };
+        failed.add_error(ErrorCategory::Storage, "connection refused");
+
+        let table = summary_table(&[ok, failed], false);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "message          user   files  destination  status",
+                "a.eml            alice  1      alice.done   ok",
+                "longer-name.eml  -      0      -            failed: storage",
+                "2 messages, 1 files stored, 1 failed",
+            ]
+        );
+        assert!(summary_table(&[], true).starts_with("message"));
+
+        let mut failed = ProcessResult::default();
+        failed.add_error(ErrorCategory::MailStore, "maildir full");
+        assert!(summary_table(&[failed], false).contains("failed: mail_store"));
+    }
+}
@@ -0,0 +1,112 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Routing rules declared in the config file.
+//!
+//! A rule matches an attachment by its vendor and the amount of the
+//! invoice. Matching attachments are additionally stored at the rendered
+//! `copy_to` template and the mail gets the flags of the rule:
+//!
+//! ```toml
+//! [[rules]]
+//! name = "approval"
+//! min_amount = 10000
+//! currency = "EUR"
+//! copy_to = "approval/{{user}}/{{file_name}}"
+//! flags = ["$Approval"]
+//! ```
+
+use crate::invoice::InvoiceData;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Rule {
+    /// Name used in the logs and the results
+    pub name: String,
+    /// Canonical vendor, see [`crate::vendor`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vendor: Option<String>,
+    /// Matches invoices with at least this total
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_amount: Option<f64>,
+    /// Matches invoices with at most this total
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_amount: Option<f64>,
+    /// Currency of the amounts, invoices in other currencies don't match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    /// Output template of an additional copy of the attachment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub copy_to: Option<String>,
+    /// Flags added to the mail
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<String>,
+}
+
+impl Rule {
+    /// Checks all conditions. Amount conditions don't match attachments
+    /// without a known total.
+    pub fn matches(&self, vendor: &str, invoice: &InvoiceData) -> bool {
+        if let Some(expected) = &self.vendor {
+            if !expected.eq_ignore_ascii_case(vendor) {
+                return false;
+            }
+        }
+        if let Some(expected) = &self.currency {
+            match &invoice.currency {
+                Some(currency) if currency.eq_ignore_ascii_case(expected) => (),
+                _ => return false,
+            }
+        }
+        if self.min_amount.is_some() || self.max_amount.is_some() {
+            let amount = match invoice.amount {
+                Some(x) => x,
+                None => return false,
+            };
+            if self.min_amount.map(|min| amount < min).unwrap_or(false)
+                || self.max_amount.map(|max| amount > max).unwrap_or(false)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Template name of the `copy_to` template of the rule
+pub(crate) fn copy_template_name(index: usize) -> String {
+    format!("rule_copy_{}", index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_matches() {
+        let rule = Rule {
+            name: "approval".to_owned(),
+            min_amount: Some(10000.0),
+            currency: Some("EUR".to_owned()),
+            ..Default::default()
+        };
+        let invoice = |amount, currency: &str| InvoiceData {
+            amount: Some(amount),
+            currency: Some(currency.to_owned()),
+            ..Default::default()
+        };
+        assert!(rule.matches("acme", &invoice(11900.0, "EUR")));
+        assert!(rule.matches("acme", &invoice(10000.0, "eur")));
+        assert!(!rule.matches("acme", &invoice(9999.99, "EUR")));
+        assert!(!rule.matches("acme", &invoice(20000.0, "USD")));
+        assert!(!rule.matches("acme", &InvoiceData::default()));
+
+        let vendor_rule = Rule {
+            name: "telekom".to_owned(),
+            vendor: Some("telekom".to_owned()),
+            ..Default::default()
+        };
+        assert!(vendor_rule.matches("telekom", &InvoiceData::default()));
+        assert!(!vendor_rule.matches("acme", &InvoiceData::default()));
+    }
+}
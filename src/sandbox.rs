@@ -0,0 +1,437 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Confinement of the processing with Landlock and seccomp (Linux).
+//!
+//! Mails and attachments are parsed by libraries that may have bugs. With
+//! `--sandbox` the process is confined once the backends are set up and
+//! before the first message is parsed:
+//!
+//! * Landlock limits the file system to the system libraries and
+//!   configuration, read only, and the configured storage, maildir,
+//!   temporary and token paths.
+//! * seccomp denies executing programs, debugging other processes,
+//!   mounting and loading kernel modules.
+//!
+//! Landlock applies to the calling thread and threads created later, so
+//! the sandbox has to be entered before worker threads are started.
+
+use crate::pipeline::{AttachmentSinkConfig, MailSinkConfig, SourceConfig};
+use crate::Config;
+use anyhow::{bail, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+
+/// Read only paths needed by name resolution, TLS and dynamic libraries
+const SYSTEM_READ_PATHS: &[&str] = &[
+    "/etc",
+    "/usr",
+    "/lib",
+    "/lib64",
+    "/proc/self",
+    "/dev/urandom",
+];
+
+// Syscall numbers are the same on all architectures
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+/// All access rights of ABI 1
+const ACCESS_FS_ABI_1: u64 = (1 << 13) - 1;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+/// Rights that can be granted on files, all others only apply to directories
+const ACCESS_FILE: u64 =
+    ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Paths the confined process may access
+#[derive(Debug, Default)]
+pub struct Sandbox {
+    read: Vec<PathBuf>,
+    write: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// Allows the local storage and maildir sinks, the temporary folder
+    /// and the OAuth token file
+    pub fn from_config(config: &Config) -> Self {
+        let mut sandbox = Self::default();
+        sandbox
+            .read
+            .extend(SYSTEM_READ_PATHS.iter().map(PathBuf::from));
+        sandbox.write.push(std::env::temp_dir());
+        let pipeline = config.pipeline_config();
+        // processed mails are moved out of the input maildir
+        if let SourceConfig::Maildir { path, done } = &pipeline.source {
+            sandbox.write.push(path.clone());
+            sandbox.write.extend(done.iter().cloned());
+        }
+        for sink in pipeline
+            .attachment_sinks
+            .iter()
+            .chain(pipeline.fallback_sinks.iter())
+        {
+            if let AttachmentSinkConfig::Local { path } = sink {
+                sandbox.write.push(path.clone());
+            }
+        }
+        for sink in pipeline.mail_sinks.iter() {
+            if let MailSinkConfig::Maildir { path } = sink {
+                sandbox.write.push(path.clone());
+            }
+        }
+        if let Some(parent) = config.oauth_token_file.as_ref().and_then(|x| x.parent()) {
+            sandbox.write.push(parent.to_owned());
+        }
+        if let Some(parent) = config.audit_log.as_ref().and_then(|x| x.parent()) {
+            sandbox.write.push(parent.to_owned());
+        }
+        if let Some(parent) = config.state_db.as_ref().and_then(|x| x.parent()) {
+            sandbox.write.push(parent.to_owned());
+        }
+        if let Some(parent) = config.fallback_manifest.as_ref().and_then(|x| x.parent()) {
+            sandbox.write.push(parent.to_owned());
+        }
+        if let Some(path) = &config.spool_dir {
+            sandbox.write.push(path.clone());
+        }
+        // SQLite writes its journal next to the database
+        if let Some(parent) = config.index_db.as_ref().and_then(|x| x.parent()) {
+            sandbox.write.push(parent.to_owned());
+        }
+        if let Some(path) = &config.dead_letter_dir {
+            sandbox.write.push(path.clone());
+        }
+        if let Some(path) = config.quarantine_path.as_ref().filter(|x| x.is_absolute()) {
+            sandbox.write.push(path.clone());
+        }
+        if let Some(parent) = config.report_file.as_ref().and_then(|x| x.parent()) {
+            sandbox.write.push(parent.to_owned());
+        }
+        sandbox.write.extend(config.sandbox_paths.iter().cloned());
+        for tenant in config.tenants.iter() {
+            if let Ok(tenant_config) = tenant.config(config) {
+                let tenant_sandbox = Self::from_config(&tenant_config);
+                sandbox.write.extend(tenant_sandbox.write);
+            }
+        }
+        sandbox
+    }
+
+    /// Allows reading the file or directory, like the input mails
+    pub fn allow_read(mut self, path: impl Into<PathBuf>) -> Self {
+        self.read.push(path.into());
+        self
+    }
+
+    /// Allows reading and changing the file or directory
+    pub fn allow_write(mut self, path: impl Into<PathBuf>) -> Self {
+        self.write.push(path.into());
+        self
+    }
+
+    /// Confines the process. Fails if a kernel feature is missing, as the
+    /// sandbox is explicitly requested.
+    pub fn apply(&self) -> Result<()> {
+        set_no_new_privs()?;
+        self.apply_landlock()?;
+        apply_seccomp()?;
+        log::info!("Sandbox enabled");
+        Ok(())
+    }
+
+    fn apply_landlock(&self) -> Result<()> {
+        // SAFETY: querying the version doesn't take an attribute
+        let abi = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_CREATE_RULESET,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            bail!(
+                "Landlock is not supported by the kernel: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        let mut handled = ACCESS_FS_ABI_1;
+        let mut write = ACCESS_FS_READ_FILE
+            | ACCESS_FS_READ_DIR
+            | ACCESS_FS_WRITE_FILE
+            | ACCESS_FS_REMOVE_DIR
+            | ACCESS_FS_REMOVE_FILE
+            | ACCESS_FS_MAKE_DIR
+            | ACCESS_FS_MAKE_REG;
+        // files are moved between the folders of a sink
+        if abi >= 2 {
+            handled |= ACCESS_FS_REFER;
+            write |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            handled |= ACCESS_FS_TRUNCATE;
+            write |= ACCESS_FS_TRUNCATE;
+        }
+        let read = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        // SAFETY: attr is valid for the call
+        let ruleset = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_CREATE_RULESET,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if ruleset < 0 {
+            bail!(
+                "Can't create Landlock ruleset: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        let ruleset = ruleset as RawFd;
+        let result = self
+            .read
+            .iter()
+            .try_for_each(|path| add_path_rule(ruleset, path, read))
+            .and_then(|_| {
+                self.write.iter().try_for_each(|path| {
+                    // storage folders may not exist before the first message
+                    std::fs::create_dir_all(path).ok();
+                    add_path_rule(ruleset, path, write)
+                })
+            })
+            .and_then(|_| {
+                // SAFETY: ruleset is a valid file descriptor
+                if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset, 0) } != 0 {
+                    bail!(
+                        "Can't enforce Landlock ruleset: {}",
+                        std::io::Error::last_os_error()
+                    );
+                }
+                Ok(())
+            });
+        // SAFETY: ruleset is owned here
+        unsafe { libc::close(ruleset) };
+        result
+    }
+}
+
+/// Allows the access beneath the path, missing paths are skipped
+fn add_path_rule(ruleset: RawFd, path: &Path, access: u64) -> Result<()> {
+    let metadata = match std::fs::metadata(path) {
+        Ok(x) => x,
+        Err(_) => {
+            log::debug!("Sandbox path {} does not exist", path.display());
+            return Ok(());
+        }
+    };
+    let access = if metadata.is_dir() {
+        access
+    } else {
+        access & ACCESS_FILE
+    };
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: c_path is a valid string
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        bail!(
+            "Can't open sandbox path {}: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        );
+    }
+    let attr = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: fd,
+    };
+    // SAFETY: attr and fd are valid for the call
+    let rc = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_ADD_RULE,
+            ruleset,
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr,
+            0,
+        )
+    };
+    unsafe { libc::close(fd) };
+    if rc != 0 {
+        bail!(
+            "Can't add sandbox rule for {}: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+/// Required to confine the process without CAP_SYS_ADMIN
+fn set_no_new_privs() -> Result<()> {
+    // SAFETY: plain prctl
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        bail!(
+            "Can't set no_new_privs: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+/// Syscalls a mail processor never needs
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_setns,
+    libc::SYS_unshare,
+    libc::SYS_bpf,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+];
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Installs a filter returning EPERM for the denied syscalls on all threads
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn apply_seccomp() -> Result<()> {
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JEQ_K: u16 = 0x15;
+    const BPF_RET_K: u16 = 0x06;
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    const SECCOMP_SET_MODE_FILTER: libc::c_uint = 1;
+    const SECCOMP_FILTER_FLAG_TSYNC: libc::c_uint = 1;
+    // offsets in struct seccomp_data
+    const OFFSET_NR: u32 = 0;
+    const OFFSET_ARCH: u32 = 4;
+
+    let stmt = |code, k| libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |k, jt, jf| libc::sock_filter {
+        code: BPF_JEQ_K,
+        jt,
+        jf,
+        k,
+    };
+    let mut filter = vec![
+        stmt(BPF_LD_W_ABS, OFFSET_ARCH),
+        jump(AUDIT_ARCH, 1, 0),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD_W_ABS, OFFSET_NR),
+    ];
+    for nr in DENIED_SYSCALLS {
+        filter.push(jump(*nr as u32, 0, 1));
+        filter.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    }
+    filter.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    // SAFETY: program points to the filter, which outlives the call
+    let rc = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_TSYNC,
+            &program as *const libc::sock_fprog,
+        )
+    };
+    if rc != 0 {
+        bail!(
+            "Can't install seccomp filter: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn apply_seccomp() -> Result<()> {
+    log::warn!("seccomp filter not supported on this architecture");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_landlock() {
+        let allowed = tempfile::tempdir().unwrap();
+        let denied = tempfile::tempdir().unwrap();
+        let allowed_path = allowed.path().to_owned();
+        let denied_path = denied.path().to_owned();
+        // Landlock confines only the thread, so the test process stays usable
+        let result = std::thread::spawn(move || {
+            set_no_new_privs().unwrap();
+            let sandbox = Sandbox::default().allow_write(&allowed_path);
+            if sandbox.apply_landlock().is_err() {
+                // kernel without Landlock
+                return None;
+            }
+            Some((
+                std::fs::write(allowed_path.join("invoice.pdf"), b"%PDF").is_ok(),
+                std::fs::write(denied_path.join("invoice.pdf"), b"%PDF").is_ok(),
+            ))
+        })
+        .join()
+        .unwrap();
+        if let Some((allowed_ok, denied_ok)) = result {
+            assert!(allowed_ok);
+            assert!(!denied_ok);
+        }
+    }
+}
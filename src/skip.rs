@@ -0,0 +1,96 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Filters of messages whose attachments are ignored.
+//!
+//! Payment reminders, newsletters and "copy of your invoice" mails often
+//! carry the same PDFs as the invoice itself. Messages whose subject
+//! matches a `--skip-subject-regex` or whose `From` matches a
+//! `--skip-from-regex` are not searched for attachments:
+//!
+//! ```toml
+//! skip_subject_regex = ["(?i)^(reminder|zahlungserinnerung)", "(?i)copy of your invoice"]
+//! skip_from_regex = ["(?i)newsletter@"]
+//! ```
+//!
+//! The message is still stored in the mail target, the mail templates have
+//! `skipped` to route it elsewhere, and the result records the reason.
+
+use crate::Config;
+use anyhow::{Context, Result};
+use mailparse::{MailHeaderMap, ParsedMail};
+use regex::Regex;
+
+/// Compiled skip patterns
+#[derive(Debug, Clone, Default)]
+pub struct SkipFilters {
+    subject: Vec<Regex>,
+    from: Vec<Regex>,
+}
+
+fn compile(patterns: &[String], option: &str) -> Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|x| Regex::new(x).with_context(|| format!("Invalid {} {:?}", option, x)))
+        .collect()
+}
+
+impl SkipFilters {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(Self {
+            subject: compile(&config.skip_subject_regex, "skip_subject_regex")?,
+            from: compile(&config.skip_from_regex, "skip_from_regex")?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subject.is_empty() && self.from.is_empty()
+    }
+
+    /// Reason the message is skipped, none if no pattern matches
+    pub fn reason(&self, subject: &str, from: &str) -> Option<String> {
+        if let Some(pattern) = self.subject.iter().find(|x| x.is_match(subject)) {
+            return Some(format!("Subject matches {}", pattern));
+        }
+        if let Some(pattern) = self.from.iter().find(|x| x.is_match(from)) {
+            return Some(format!("From matches {}", pattern));
+        }
+        None
+    }
+
+    /// Reason the parsed message is skipped
+    pub fn check(&self, message: &ParsedMail) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+        let header = |name: &str| message.headers.get_first_value(name).unwrap_or_default();
+        self.reason(&header("subject"), &header("from"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_filters() {
+        let filters = SkipFilters {
+            subject: compile(&["(?i)^reminder".to_owned()], "skip_subject_regex").unwrap(),
+            from: compile(&["newsletter@".to_owned()], "skip_from_regex").unwrap(),
+        };
+        assert_eq!(filters.reason("Invoice 42", "billing@example.com"), None);
+        assert_eq!(
+            filters.reason("REMINDER: Invoice 42", "billing@example.com"),
+            Some("Subject matches (?i)^reminder".to_owned())
+        );
+        assert_eq!(
+            filters.reason("News", "Shop <newsletter@example.com>"),
+            Some("From matches newsletter@".to_owned())
+        );
+        assert!(compile(&["(".to_owned()], "skip_from_regex").is_err());
+
+        let message = mailparse::parse_mail(b"Subject: Reminder\r\n\r\n").unwrap();
+        assert!(filters.check(&message).is_some());
+        assert_eq!(SkipFilters::default().check(&message), None);
+    }
+}
@@ -0,0 +1,60 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Detects the type of an attachment by the magic bytes of its content.
+//! Senders often label invoices as `application/octet-stream`, with
+//! `--sniff-content` such attachments are matched by the detected type.
+
+/// Number of decoded bytes needed to detect all known types
+pub const SNIFF_LENGTH: usize = 512;
+
+/// Magic bytes at the start of the content and their mimetype
+const MAGIC: [(&[u8], &str); 9] = [
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF8", "image/gif"),
+    (b"II*\x00", "image/tiff"),
+    (b"MM\x00*", "image/tiff"),
+];
+
+/// Returns the mimetype of the content, `None` if the type is unknown
+pub fn detect(head: &[u8]) -> Option<&'static str> {
+    if let Some((_, mimetype)) = MAGIC.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return Some(*mimetype);
+    }
+    // some PDF writers put a few bytes of garbage in front of the header
+    if head.windows(5).any(|x| x == b"%PDF-") {
+        return Some("application/pdf");
+    }
+    let text = head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(head);
+    let text = &text[text
+        .iter()
+        .position(|x| !x.is_ascii_whitespace())
+        .unwrap_or(text.len())..];
+    if text.starts_with(b"<?xml") {
+        return Some("application/xml");
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect(b"%PDF-1.7\n%\xe2\xe3"), Some("application/pdf"));
+        assert_eq!(detect(b"\r\n%PDF-1.4"), Some("application/pdf"));
+        assert_eq!(detect(b"PK\x03\x04\x14\x00"), Some("application/zip"));
+        assert_eq!(
+            detect(b"\xef\xbb\xbf\n<?xml version=\"1.0\"?><Invoice/>"),
+            Some("application/xml")
+        );
+        assert_eq!(detect(b"Dear customer"), None);
+        assert_eq!(detect(b""), None);
+    }
+}
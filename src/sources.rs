@@ -0,0 +1,726 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Message sources of the [`Pipeline`](crate::pipeline::Pipeline)
+
+use crate::oauth::OAuthClient;
+use crate::pipeline::{Message, Source};
+use crate::result::{ErrorCategory, ProcessResult};
+use crate::{imap_flag_list, CancellationToken, ImapConnection, ImapNamespace, ImapSession};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use imap::types::Uid;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Reads the raw message from the file or stdin if the file name is `-`
+pub fn read_input(file_name: &str) -> Result<Bytes> {
+    let content = if file_name == "-" {
+        let mut content = Vec::new();
+        std::io::stdin()
+            .lock()
+            .read_to_end(&mut content)
+            .context("Can't read stdin")?;
+        content
+    } else {
+        std::fs::read(file_name).with_context(|| format!("Can't read file: {}", file_name))?
+    };
+    Ok(Bytes::from(content))
+}
+
+/// Reads a single message from stdin
+#[derive(Debug, Default)]
+pub struct StdinSource {
+    done: bool,
+}
+
+#[async_trait]
+impl Source for StdinSource {
+    async fn next_message(&mut self) -> Result<Option<Message>> {
+        if self.done {
+            return Ok(None);
+        }
+        self.done = true;
+        Ok(Some(Message {
+            id: "stdin".to_owned(),
+            content: read_input("-")?,
+            mailbox: None,
+        }))
+    }
+}
+
+/// Reads a single message from a file
+#[derive(Debug)]
+pub struct FileSource {
+    path: PathBuf,
+    done: bool,
+}
+
+impl FileSource {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, done: false }
+    }
+}
+
+#[async_trait]
+impl Source for FileSource {
+    async fn next_message(&mut self) -> Result<Option<Message>> {
+        if self.done {
+            return Ok(None);
+        }
+        self.done = true;
+        let content = tokio::fs::read(&self.path)
+            .await
+            .with_context(|| format!("Can't read file: {}", self.path.display()))?;
+        Ok(Some(Message {
+            id: self.path.display().to_string(),
+            content: content.into(),
+            mailbox: None,
+        }))
+    }
+}
+
+/// Reads all mails of the given files and directories. Directories are
+/// searched recursively for `*.eml` files.
+#[derive(Debug)]
+pub struct FilesSource {
+    files: std::vec::IntoIter<PathBuf>,
+}
+
+impl FilesSource {
+    pub fn new(paths: &[PathBuf]) -> Result<Self> {
+        let mut files = Vec::new();
+        for path in paths.iter() {
+            if path.is_dir() {
+                collect_mail_files(path, &mut files)?;
+            } else {
+                files.push(path.clone());
+            }
+        }
+        Ok(Self {
+            files: files.into_iter(),
+        })
+    }
+}
+
+/// Adds all `*.eml` files below the directory in a stable order
+fn collect_mail_files(dir: &std::path::Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Can't read directory: {}", dir.display()))?
+        .map(|entry| entry.map(|x| x.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            collect_mail_files(&path, files)?;
+        } else if path
+            .extension()
+            .map_or(false, |x| x.eq_ignore_ascii_case("eml"))
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Source for FilesSource {
+    async fn next_message(&mut self) -> Result<Option<Message>> {
+        let path = match self.files.next() {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let content = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("Can't read file: {}", path.display()))?;
+        Ok(Some(Message {
+            id: path.display().to_string(),
+            content: content.into(),
+            mailbox: None,
+        }))
+    }
+}
+
+/// Reads all mails of an mbox file, like a Thunderbird archive. Mails are
+/// separated by `From ` lines after an empty line, quoted `>From ` lines
+/// are unquoted. The file is read line by line, so large archives don't
+/// have to fit into memory.
+#[derive(Debug)]
+pub struct MboxSource {
+    path: PathBuf,
+    reader: BufReader<std::fs::File>,
+    /// Number of mails read, used in the message ids
+    count: usize,
+    /// The separator of the next mail was already read
+    started: bool,
+}
+
+impl MboxSource {
+    pub fn new(path: &std::path::Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Can't read mbox: {}", path.display()))?;
+        Ok(Self {
+            path: path.to_owned(),
+            reader: BufReader::new(file),
+            count: 0,
+            started: false,
+        })
+    }
+
+    /// Reads the lines up to the next separator
+    fn read_mail(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut content = Vec::new();
+        let mut previous_empty = true;
+        loop {
+            let mut line = Vec::new();
+            let read = self
+                .reader
+                .read_until(b'\n', &mut line)
+                .with_context(|| format!("Can't read mbox: {}", self.path.display()))?;
+            if read == 0 {
+                if content.is_empty() && !self.started {
+                    return Ok(None);
+                }
+                self.started = false;
+                break;
+            }
+            if line.starts_with(b"From ") && previous_empty {
+                if content.is_empty() && !self.started {
+                    self.started = true;
+                    continue;
+                }
+                // the separator belongs to the next mail
+                self.started = true;
+                break;
+            }
+            previous_empty = line == b"\n" || line == b"\r\n";
+            let quoted = line.iter().position(|x| *x != b'>').unwrap_or(0);
+            if quoted > 0 && line[quoted..].starts_with(b"From ") {
+                content.extend_from_slice(&line[1..]);
+            } else {
+                content.extend_from_slice(&line);
+            }
+        }
+        // the empty line before the separator
+        if content.ends_with(b"\r\n\r\n") {
+            content.truncate(content.len() - 2);
+        } else if content.ends_with(b"\n\n") {
+            content.truncate(content.len() - 1);
+        }
+        Ok(Some(content))
+    }
+}
+
+#[async_trait]
+impl Source for MboxSource {
+    async fn next_message(&mut self) -> Result<Option<Message>> {
+        let content = match self.read_mail()? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        self.count += 1;
+        Ok(Some(Message {
+            id: format!("{}#{}", self.path.display(), self.count),
+            content: content.into(),
+            mailbox: None,
+        }))
+    }
+}
+
+/// Reads all mails of a maildir, first `new` then `cur`. Successfully
+/// processed mails are moved to the `cur` folder of the done maildir, the
+/// others stay in place for the next run.
+#[derive(Debug)]
+pub struct MaildirSource {
+    files: std::vec::IntoIter<PathBuf>,
+    done: Option<PathBuf>,
+    /// File of the mail returned by the last `next_message`
+    current: Option<PathBuf>,
+}
+
+impl MaildirSource {
+    pub fn new(path: &std::path::Path, done: Option<PathBuf>) -> Result<Self> {
+        let mut files = Vec::new();
+        for folder in ["new", "cur"] {
+            let folder = path.join(folder);
+            let mut entries = std::fs::read_dir(&folder)
+                .with_context(|| format!("Can't read maildir folder: {}", folder.display()))?
+                .map(|entry| entry.map(|x| x.path()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            // dot files are temporary files of some delivery agents
+            entries.retain(|x| {
+                x.is_file()
+                    && !x
+                        .file_name()
+                        .map_or(true, |name| name.to_string_lossy().starts_with('.'))
+            });
+            entries.sort();
+            files.extend(entries);
+        }
+        if let Some(done) = &done {
+            for folder in ["new", "cur", "tmp"] {
+                std::fs::create_dir_all(done.join(folder))
+                    .with_context(|| format!("Can't create maildir: {}", done.display()))?;
+            }
+        }
+        Ok(Self {
+            files: files.into_iter(),
+            done,
+            current: None,
+        })
+    }
+}
+
+/// File name of the mail in `cur`, mails of `new` get an empty info part
+fn cur_file_name(path: &std::path::Path) -> Option<String> {
+    let name = path.file_name()?.to_string_lossy().into_owned();
+    let in_new = path
+        .parent()
+        .and_then(|x| x.file_name())
+        .map_or(false, |x| x == "new");
+    if in_new && !name.contains(":2,") {
+        Some(format!("{}:2,", name))
+    } else {
+        Some(name)
+    }
+}
+
+#[async_trait]
+impl Source for MaildirSource {
+    async fn next_message(&mut self) -> Result<Option<Message>> {
+        let path = match self.files.next() {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let content = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("Can't read file: {}", path.display()))?;
+        self.current = Some(path.clone());
+        Ok(Some(Message {
+            id: path.display().to_string(),
+            content: content.into(),
+            mailbox: None,
+        }))
+    }
+
+    async fn finish(&mut self, _message: &Message, result: &ProcessResult) -> Result<()> {
+        let (path, done) = match (self.current.take(), &self.done) {
+            (Some(path), Some(done)) => (path, done),
+            _ => return Ok(()),
+        };
+        if !result.is_success() {
+            return Ok(());
+        }
+        let name = cur_file_name(&path).context("Mail file without name")?;
+        let target = done.join("cur").join(name);
+        tokio::fs::rename(&path, &target)
+            .await
+            .with_context(|| format!("Can't move {} to {}", path.display(), target.display()))?;
+        log::debug!("Moved {} to {}", path.display(), target.display());
+        Ok(())
+    }
+}
+
+/// How mails are moved out of the fetched folder. The folder isn't owned
+/// by invoice2storage, so only the moved mail may be expunged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MoveCommand {
+    /// `UID MOVE` of RFC 6851
+    Move,
+    /// `UID COPY` and `UID EXPUNGE` of the mail with UIDPLUS
+    UidExpunge,
+    /// `UID COPY`, the original is only flagged `\Deleted`
+    Copy,
+}
+
+impl MoveCommand {
+    fn from_capabilities(has: impl Fn(&str) -> bool) -> Self {
+        if has("MOVE") {
+            Self::Move
+        } else if has("UIDPLUS") {
+            Self::UidExpunge
+        } else {
+            Self::Copy
+        }
+    }
+}
+
+/// Fetches the unseen mails of IMAP folders. Processed mails are moved to
+/// the folder of the rendered `mail_template` with the result flags, so
+/// the server keeps the same layout as with the IMAP mail sink. Mails that
+/// couldn't be stored stay unseen and are fetched again by the next pass.
+pub struct ImapSource {
+    connection: ImapConnection,
+    oauth: Option<Arc<OAuthClient>>,
+    folders: Vec<String>,
+    /// Configured parent folder and separator of the target folders
+    prefix: Option<String>,
+    separator: Option<String>,
+    /// Namespace completed by the server on the first move
+    namespace: Option<ImapNamespace>,
+    /// Detected with the capabilities on the first move
+    move_command: Option<MoveCommand>,
+    interval: Option<Duration>,
+    cancel: CancellationToken,
+    selected: Option<String>,
+    /// Unseen mails of the last search by folder
+    pending: VecDeque<(String, Uid)>,
+    /// Mail returned by the last `next_message`
+    current: Option<(String, Uid)>,
+    searched: bool,
+}
+
+impl std::fmt::Debug for ImapSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImapSource")
+            .field("folders", &self.folders)
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ImapSource {
+    pub fn new(url: String, tls: Arc<rustls::ClientConfig>, folders: Vec<String>) -> Self {
+        Self {
+            connection: ImapConnection::new(url, tls),
+            oauth: None,
+            folders,
+            prefix: None,
+            separator: None,
+            namespace: None,
+            move_command: None,
+            interval: None,
+            cancel: CancellationToken::new(),
+            selected: None,
+            pending: VecDeque::new(),
+            current: None,
+            searched: false,
+        }
+    }
+
+    /// Log in with XOAUTH2 instead of the password of the url
+    pub fn with_oauth(mut self, oauth: Arc<OAuthClient>) -> Self {
+        self.oauth = Some(oauth);
+        self
+    }
+
+    /// Searches the folders again after the interval instead of ending
+    /// after the first pass
+    pub fn with_interval(mut self, interval: Option<Duration>) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Parent folder and separator of the target folders, the unset ones
+    /// are asked from the server
+    pub fn with_namespace(mut self, prefix: Option<String>, separator: Option<String>) -> Self {
+        self.prefix = prefix;
+        self.separator = separator;
+        self
+    }
+
+    /// Connects on first use and selects the folder, a lost connection
+    /// is logged in again
+    async fn select(&mut self, folder: &str) -> Result<&mut ImapSession> {
+        let token = match &self.oauth {
+            Some(oauth) => Some(oauth.access_token().await?),
+            None => None,
+        };
+        let (session, login) = self.connection.session(token.as_deref())?;
+        if login {
+            self.selected = None;
+        }
+        if self.selected.as_deref() != Some(folder) {
+            session
+                .select(folder)
+                .with_context(|| format!("Can't select folder {}", folder))?;
+            self.selected = Some(folder.to_owned());
+        }
+        Ok(session)
+    }
+
+    /// Queues the unseen mails of all folders
+    async fn search(&mut self) -> Result<()> {
+        for folder in self.folders.clone() {
+            let session = self.select(&folder).await?;
+            let mut uids: Vec<Uid> = session.uid_search("UNSEEN")?.into_iter().collect();
+            uids.sort_unstable();
+            log::debug!("{} unseen mails in {}", uids.len(), folder);
+            self.pending
+                .extend(uids.into_iter().map(|uid| (folder.clone(), uid)));
+        }
+        Ok(())
+    }
+
+    /// Fetches the mail without setting the seen flag, `None` if it was
+    /// deleted in the meantime
+    async fn fetch(&mut self, folder: &str, uid: Uid) -> Result<Option<Bytes>> {
+        let session = self.select(folder).await?;
+        let fetches = session.uid_fetch(uid.to_string(), "BODY.PEEK[]")?;
+        Ok(fetches
+            .iter()
+            .find_map(|x| x.body())
+            .map(Bytes::copy_from_slice))
+    }
+
+    /// Drops the session and the queued mails after a failure while
+    /// polling, the next pass reconnects
+    fn reset(&mut self, err: anyhow::Error) {
+        log::error!("Can't fetch IMAP mails: {:#}", err);
+        self.connection.close();
+        self.pending.clear();
+    }
+
+    /// Mailbox of the rendered target, the namespace is detected once
+    async fn mailbox(&mut self, folder: &str, target: &str) -> Result<String> {
+        if self.namespace.is_none() {
+            let (prefix, separator) = (self.prefix.clone(), self.separator.clone());
+            let session = self.select(folder).await?;
+            self.namespace = Some(ImapNamespace::detect(
+                session,
+                prefix.as_deref(),
+                separator.as_deref(),
+            )?);
+        }
+        Ok(self
+            .namespace
+            .as_ref()
+            .map(|x| x.mailbox(target))
+            .unwrap_or_default())
+    }
+
+    /// Sets the flags and moves the mail to the target folder, the
+    /// folder is created if missing
+    async fn file(&mut self, folder: &str, uid: Uid, target: &str, flags: &[String]) -> Result<()> {
+        let move_command = self.move_command;
+        let session = self.select(folder).await?;
+        let uid = uid.to_string();
+        let mut flags = flags.to_vec();
+        if target == folder {
+            // keep it from being fetched again
+            flags.push("\\Seen".to_owned());
+        }
+        if !flags.is_empty() {
+            session.uid_store(&uid, format!("+FLAGS {}", imap_flag_list(&flags)))?;
+        }
+        if target == folder {
+            return Ok(());
+        }
+        let command = match move_command {
+            Some(x) => x,
+            None => {
+                let capabilities = session.capabilities()?;
+                MoveCommand::from_capabilities(|x| capabilities.has_str(x))
+            }
+        };
+        let transfer = |session: &mut ImapSession| match command {
+            MoveCommand::Move => session.uid_mv(&uid, target),
+            _ => session.uid_copy(&uid, target),
+        };
+        if transfer(&mut *session).is_err() {
+            log::info!("Creating target folder: {}", target);
+            session
+                .create(target)
+                .with_context(|| format!("Can't create target folder {}", target))?;
+            transfer(&mut *session).with_context(|| format!("Can't move mail to {}", target))?;
+        }
+        match command {
+            MoveCommand::Move => (),
+            MoveCommand::UidExpunge => {
+                session.uid_store(&uid, "+FLAGS (\\Deleted)")?;
+                session.uid_expunge(&uid)?;
+            }
+            MoveCommand::Copy => {
+                // without UIDPLUS an EXPUNGE would remove all deleted mails
+                log::info!("Server has no MOVE or UIDPLUS, the original is left flagged deleted");
+                session.uid_store(&uid, "+FLAGS (\\Seen \\Deleted)")?;
+            }
+        }
+        self.move_command = Some(command);
+        log::info!("Message moved to mailbox: {}", target);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Source for ImapSource {
+    async fn next_message(&mut self) -> Result<Option<Message>> {
+        loop {
+            if let Some((folder, uid)) = self.pending.pop_front() {
+                let content = match self.fetch(&folder, uid).await {
+                    Ok(Some(x)) => x,
+                    Ok(None) => continue,
+                    Err(err) if self.interval.is_some() => {
+                        self.reset(err);
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+                self.current = Some((folder.clone(), uid));
+                return Ok(Some(Message {
+                    id: format!("imap:{}/{}", folder, uid),
+                    content,
+                    mailbox: Some(folder),
+                }));
+            }
+            if self.searched {
+                let interval = match self.interval {
+                    Some(x) => x,
+                    None => return Ok(None),
+                };
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => (),
+                    _ = self.cancel.cancelled() => return Ok(None),
+                }
+            }
+            self.searched = true;
+            if let Err(err) = self.search().await {
+                if self.interval.is_none() {
+                    return Err(err);
+                }
+                self.reset(err);
+            }
+        }
+    }
+
+    async fn finish(&mut self, message: &Message, result: &ProcessResult) -> Result<()> {
+        let (folder, uid) = match self.current.take() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let retry = result.errors.iter().any(|x| {
+            matches!(
+                x.category,
+                ErrorCategory::MailStore | ErrorCategory::Cancelled
+            )
+        });
+        if retry {
+            log::warn!("{} stays unseen and is fetched again", message.id);
+            return Ok(());
+        }
+        // mails that weren't filed, like with extract_only, stay in place
+        let filed = match &result.mailbox {
+            Some(mailbox) => self.mailbox(&folder, mailbox).await,
+            None => Ok(folder.clone()),
+        };
+        let filed = match filed {
+            Ok(target) => self.file(&folder, uid, &target, &result.flags).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = filed {
+            self.connection.close();
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn set_cancellation(&mut self, cancel: CancellationToken) {
+        self.cancel = cancel;
+    }
+
+    async fn check(&mut self) -> Result<()> {
+        let token = match &self.oauth {
+            Some(oauth) => Some(oauth.access_token().await?),
+            None => None,
+        };
+        let (session, _) = self.connection.session(token.as_deref())?;
+        let folders = match session.list(Some(""), Some("*")) {
+            Ok(x) => x,
+            Err(err) => {
+                self.connection.close();
+                return Err(err).context("Can't list folders");
+            }
+        };
+        log::debug!("IMAP mailbox has {} folders", folders.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mbox_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.mbox");
+        std::fs::write(
+            &path,
+            b"From alice@example.com Mon Jan  2 10:00:00 2023\n\
+              Subject: first\n\n\
+              >From the archive\n\
+              From is not quoted by every client\n\n\
+              From bob@example.com Tue Jan  3 10:00:00 2023\n\
+              Subject: second\n\nbody\n",
+        )
+        .unwrap();
+        let mut source = MboxSource::new(&path).unwrap();
+        let mut mails = Vec::new();
+        while let Some(message) = source.next_message().await.unwrap() {
+            mails.push((message.id, message.content));
+        }
+        assert_eq!(mails.len(), 2);
+        assert!(mails[0].0.ends_with("archive.mbox#1"));
+        assert_eq!(
+            mails[0].1,
+            &b"Subject: first\n\nFrom the archive\nFrom is not quoted by every client\n"[..]
+        );
+        assert_eq!(mails[1].1, &b"Subject: second\n\nbody\n"[..]);
+    }
+
+    #[tokio::test]
+    async fn test_maildir_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        let done = dir.path().join("done");
+        for folder in ["new", "cur", "tmp"] {
+            std::fs::create_dir_all(input.join(folder)).unwrap();
+        }
+        std::fs::write(input.join("new/1.host"), b"Subject: a\r\n\r\n").unwrap();
+        std::fs::write(input.join("cur/2.host:2,S"), b"Subject: b\r\n\r\n").unwrap();
+
+        let mut source = MaildirSource::new(&input, Some(done.clone())).unwrap();
+        let ok = ProcessResult::default();
+        let mut failed = ProcessResult::default();
+        failed.add_error(ErrorCategory::Storage, "failed");
+        for result in [ok, failed] {
+            let message = source.next_message().await.unwrap().unwrap();
+            source.finish(&message, &result).await.unwrap();
+        }
+        assert!(source.next_message().await.unwrap().is_none());
+        assert!(done.join("cur/1.host:2,").exists());
+        assert!(input.join("cur/2.host:2,S").exists());
+    }
+
+    #[tokio::test]
+    async fn test_files_source() {
+        let mut source = FilesSource::new(&["test-data".into()]).unwrap();
+        let mut ids = Vec::new();
+        while let Some(message) = source.next_message().await.unwrap() {
+            assert!(!message.content.is_empty());
+            ids.push(message.id);
+        }
+        assert_eq!(
+            ids,
+            vec![
+                "test-data/test_email1.eml",
+                "test-data/test_email1_broken.eml",
+                "test-data/test_email_no_attachment.eml",
+                "test-data/test_email_no_plus.eml",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_move_command() {
+        let from =
+            |capabilities: &[&str]| MoveCommand::from_capabilities(|x| capabilities.contains(&x));
+        assert_eq!(from(&["IMAP4rev1", "UIDPLUS", "MOVE"]), MoveCommand::Move);
+        assert_eq!(from(&["IMAP4rev1", "UIDPLUS"]), MoveCommand::UidExpunge);
+        assert_eq!(from(&["IMAP4rev1"]), MoveCommand::Copy);
+    }
+}
@@ -0,0 +1,206 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! State database of stored files.
+//!
+//! Every stored attachment is appended as a JSON line with the SHA-256 of
+//! its content, so identical invoices can be found later, for example the
+//! same invoice sent to two users, which may otherwise be paid twice.
+
+use crate::{FileStatus, ProcessResult};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One stored file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StoredFile {
+    /// Unix timestamp
+    pub time: u64,
+    /// Hex encoded SHA-256 of the content
+    pub sha256: String,
+    pub size: usize,
+    pub user: Option<String>,
+    /// Rendered output path
+    pub path: String,
+    /// Id of the message given by the source
+    pub message: Option<String>,
+}
+
+impl StoredFile {
+    /// Folder of the output path, empty for files in the root
+    pub fn folder(&self) -> &str {
+        self.path
+            .rsplit_once('/')
+            .map(|(x, _)| x)
+            .unwrap_or_default()
+    }
+}
+
+/// Appends the stored files of all results. Appends are serialized with a
+/// file lock, so several processes can share the database.
+#[derive(Debug, Clone)]
+pub struct StateDb {
+    path: PathBuf,
+}
+
+impl StateDb {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Records the stored files of the result
+    pub fn record(&self, result: &ProcessResult) -> Result<()> {
+        let stored: Vec<_> = result
+            .attachments
+            .iter()
+            .filter(|x| x.status == FileStatus::Stored)
+            .collect();
+        if stored.is_empty() {
+            return Ok(());
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+        let mut lines = Vec::new();
+        for attachment in stored {
+            let entry = StoredFile {
+                time,
+                sha256: attachment.sha256.clone(),
+                size: attachment.size,
+                user: result.user.clone(),
+                path: attachment.path.clone(),
+                message: result.message.clone(),
+            };
+            serde_json::to_writer(&mut lines, &entry)?;
+            lines.push(b'\n');
+        }
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .with_context(|| format!("Can't open state database {}", self.path.display()))?;
+        crate::audit::lock(&file)?;
+        file.write_all(&lines)
+            .with_context(|| format!("Can't write state database {}", self.path.display()))?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// Reads all entries. Damaged lines, like a partial write, are skipped.
+    pub fn entries(&self) -> Result<Vec<StoredFile>> {
+        read_entries(&self.path)
+    }
+
+    /// Hashes of all stored files, empty before the first file is recorded
+    pub fn hashes(&self) -> Result<HashSet<String>> {
+        if !self.path.exists() {
+            return Ok(HashSet::new());
+        }
+        Ok(self
+            .entries()?
+            .into_iter()
+            .map(|x| x.sha256)
+            .filter(|x| !x.is_empty())
+            .collect())
+    }
+}
+
+fn read_entries(path: &Path) -> Result<Vec<StoredFile>> {
+    let file = File::open(path)
+        .with_context(|| format!("Can't open state database {}", path.display()))?;
+    let mut rv = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(entry) => rv.push(entry),
+            Err(err) => log::warn!("{}:{}: skipping entry: {}", path.display(), number + 1, err),
+        }
+    }
+    Ok(rv)
+}
+
+/// Files with the same content
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Duplicate {
+    pub sha256: String,
+    pub size: usize,
+    pub files: Vec<StoredFile>,
+}
+
+/// Groups the entries by content and returns the contents filed under more
+/// than one user or folder. Copies of the same message, like the copies of
+/// routing rules, are not reported.
+pub fn duplicates(entries: Vec<StoredFile>) -> Vec<Duplicate> {
+    let mut by_hash: BTreeMap<String, Vec<StoredFile>> = BTreeMap::new();
+    for entry in entries.into_iter().filter(|x| !x.sha256.is_empty()) {
+        by_hash.entry(entry.sha256.clone()).or_default().push(entry);
+    }
+    by_hash
+        .into_iter()
+        .filter(|(_, files)| {
+            files.iter().any(|x| {
+                x.user != files[0].user
+                    || (x.folder() != files[0].folder() && x.message != files[0].message)
+            })
+        })
+        .map(|(sha256, files)| Duplicate {
+            sha256,
+            size: files[0].size,
+            files,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileResult;
+
+    #[test]
+    fn test_duplicates() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = StateDb::new(dir.path().join("state.jsonl"));
+        let file = |path: &str, sha256: &str| FileResult {
+            file_name: "invoice.pdf".to_owned(),
+            mimetype: "application/pdf".to_owned(),
+            path: path.to_owned(),
+            size: 10,
+            sha256: sha256.to_owned(),
+            ..Default::default()
+        };
+        let mut alice = ProcessResult {
+            message: Some("a.eml".to_owned()),
+            user: Some("alice".to_owned()),
+            ..Default::default()
+        };
+        alice.add_file(file("alice/invoice.pdf", "aa"));
+        alice.add_file(file("approval/alice/invoice.pdf", "aa"));
+        alice.add_file(file("alice/other.pdf", "bb"));
+        let mut bob = ProcessResult {
+            message: Some("b.eml".to_owned()),
+            user: Some("bob".to_owned()),
+            ..Default::default()
+        };
+        bob.add_file(file("bob/invoice.pdf", "aa"));
+        db.record(&alice).unwrap();
+        db.record(&bob).unwrap();
+
+        let found = duplicates(db.entries().unwrap());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].sha256, "aa");
+        assert_eq!(found[0].files.len(), 3);
+
+        // the copy of a routing rule alone is no duplicate
+        let found = duplicates(db.entries().unwrap().into_iter().take(3).collect());
+        assert!(found.is_empty());
+    }
+}
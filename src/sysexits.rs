@@ -0,0 +1,166 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Exit codes of `sysexits.h` for MTAs and local delivery agents.
+//!
+//! Without `--sysexits` every failed message exits with 1. Postfix,
+//! procmail and other MTAs bounce such messages. With `--sysexits` the exit
+//! code tells them what went wrong, so messages that failed for temporary
+//! reasons are queued and delivered again:
+//!
+//! | category                                                   | code              |
+//! |------------------------------------------------------------|-------------------|
+//! | `input`, `directory`, `template`, `storage`, `mail_store`, `cancelled` | 75 `EX_TEMPFAIL` |
+//! | `parse`, `attachment`, `password`, `no_attachment`         | 65 `EX_DATAERR`   |
+//! | `hook`                                                     | 70 `EX_SOFTWARE`  |
+//! | `output`                                                   | 74 `EX_IOERR`     |
+//!
+//! Messages without a matching attachment are handled and exit with 0
+//! `EX_OK`, unless `--on-no-attachment error` is set. Setup failures, like
+//! an unreachable database, exit with `EX_TEMPFAIL`, so no mail is bounced
+//! while the configuration is fixed.
+//! The `[exit_codes]` table of the config file overrides the code of a
+//! category:
+//!
+//! ```toml
+//! sysexits = true
+//!
+//! [exit_codes]
+//! attachment = 0
+//! ```
+//!
+//! If messages of a run fail with different codes, `EX_TEMPFAIL` wins over
+//! the others and the code of the first failed message is used otherwise.
+
+use crate::{Config, ErrorCategory, ProcessResult};
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap};
+
+pub const EX_OK: u8 = 0;
+pub const EX_DATAERR: u8 = 65;
+pub const EX_SOFTWARE: u8 = 70;
+pub const EX_IOERR: u8 = 74;
+pub const EX_TEMPFAIL: u8 = 75;
+
+/// Exit code of failed messages without `--sysexits`
+pub const EXIT_FAILURE: u8 = 1;
+
+/// Code of the category without override
+pub fn default_code(category: ErrorCategory) -> u8 {
+    match category {
+        ErrorCategory::Input
+        | ErrorCategory::Directory
+        | ErrorCategory::Template
+        | ErrorCategory::Storage
+        | ErrorCategory::MailStore
+        | ErrorCategory::Cancelled => EX_TEMPFAIL,
+        ErrorCategory::Parse
+        | ErrorCategory::Attachment
+        | ErrorCategory::Password
+        | ErrorCategory::NoAttachment => EX_DATAERR,
+        ErrorCategory::Hook => EX_SOFTWARE,
+        ErrorCategory::Output => EX_IOERR,
+    }
+}
+
+/// Parses the category names of the `[exit_codes]` table
+pub fn parse_overrides(table: &BTreeMap<String, u8>) -> Result<HashMap<ErrorCategory, u8>> {
+    table
+        .iter()
+        .map(|(name, code)| {
+            let category: ErrorCategory =
+                serde_json::from_value(serde_json::Value::String(name.clone()))
+                    .with_context(|| format!("Unknown error category {:?} in exit_codes", name))?;
+            Ok((category, *code))
+        })
+        .collect()
+}
+
+/// Code of the run after a message exited with `next`
+pub fn combine(code: u8, next: u8) -> u8 {
+    match (code, next) {
+        (_, EX_TEMPFAIL) => EX_TEMPFAIL,
+        (EX_OK, next) => next,
+        (code, _) => code,
+    }
+}
+
+/// Maps the results to exit codes
+#[derive(Debug, Clone, Default)]
+pub struct ExitCodes {
+    /// `sysexits.h` codes instead of 1 for failures
+    enabled: bool,
+    overrides: HashMap<ErrorCategory, u8>,
+}
+
+impl ExitCodes {
+    /// Codes of `sysexits` and `exit_codes`, unknown categories are left
+    /// to [`Config::problems`]
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            enabled: config.sysexits,
+            overrides: parse_overrides(&config.exit_codes).unwrap_or_default(),
+        }
+    }
+
+    pub fn code(&self, category: ErrorCategory) -> u8 {
+        match self.overrides.get(&category) {
+            Some(code) => *code,
+            None => default_code(category),
+        }
+    }
+
+    /// Exit code of one message
+    pub fn for_result(&self, result: &ProcessResult) -> u8 {
+        if result.is_success() {
+            return EX_OK;
+        }
+        if !self.enabled {
+            return EXIT_FAILURE;
+        }
+        result.errors.iter().fold(EX_OK, |code, error| {
+            combine(code, self.code(error.category))
+        })
+    }
+
+    /// Exit code if the processing can't be set up
+    pub fn setup_failed(&self) -> u8 {
+        if self.enabled {
+            EX_TEMPFAIL
+        } else {
+            EXIT_FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        let mut result = ProcessResult::default();
+        let plain = ExitCodes::default();
+        let sysexits = ExitCodes {
+            enabled: true,
+            ..Default::default()
+        };
+        assert_eq!(sysexits.for_result(&result), EX_OK);
+
+        result.add_error(ErrorCategory::Attachment, "invalid base64");
+        assert_eq!(plain.for_result(&result), EXIT_FAILURE);
+        assert_eq!(sysexits.for_result(&result), EX_DATAERR);
+        result.add_error(ErrorCategory::Storage, "connection refused");
+        assert_eq!(sysexits.for_result(&result), EX_TEMPFAIL);
+        assert_eq!(combine(EX_DATAERR, EX_SOFTWARE), EX_DATAERR);
+
+        let table = BTreeMap::from([("storage".to_owned(), 0), ("attachment".to_owned(), 0)]);
+        let lenient = ExitCodes {
+            enabled: true,
+            overrides: parse_overrides(&table).unwrap(),
+        };
+        assert_eq!(lenient.for_result(&result), EX_OK);
+        let table = BTreeMap::from([("upload".to_owned(), 0)]);
+        assert!(parse_overrides(&table).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Log lines to the system log.
+//!
+//! Run as MDA filter the stderr of the pipe is often discarded. With
+//! `--log-target syslog` the log lines are sent to `/dev/log` with the
+//! ident and facility of `syslog_ident` and `syslog_facility`, with
+//! `--log-target journald` to the native journal socket, so `journalctl
+//! -t invoice2storage` shows them:
+//!
+//! ```toml
+//! log_target = "syslog"
+//! syslog_facility = "mail"
+//! ```
+//!
+//! The severity follows the level of the line. The format of the lines is
+//! still chosen with `--log-format`, json lines are sent as message.
+
+use anyhow::{bail, Result};
+use std::fmt::Write;
+
+/// Facility of MDA programs like procmail
+pub const DEFAULT_SYSLOG_FACILITY: &str = "mail";
+
+/// Identifier of the log lines
+pub const DEFAULT_SYSLOG_IDENT: &str = "invoice2storage";
+
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+#[cfg(unix)]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+const FACILITIES: &[(&str, u8)] = &[
+    ("kern", 0),
+    ("user", 1),
+    ("mail", 2),
+    ("daemon", 3),
+    ("auth", 4),
+    ("syslog", 5),
+    ("lpr", 6),
+    ("news", 7),
+    ("uucp", 8),
+    ("cron", 9),
+    ("authpriv", 10),
+    ("ftp", 11),
+    ("local0", 16),
+    ("local1", 17),
+    ("local2", 18),
+    ("local3", 19),
+    ("local4", 20),
+    ("local5", 21),
+    ("local6", 22),
+    ("local7", 23),
+];
+
+/// Code of a facility name like `mail` or `local0`
+pub fn facility_code(name: &str) -> Result<u8> {
+    match FACILITIES
+        .iter()
+        .find(|x| x.0.eq_ignore_ascii_case(name.trim()))
+    {
+        Some((_, code)) => Ok(*code),
+        None => bail!("Unknown syslog facility {:?}", name),
+    }
+}
+
+/// Syslog severity of the level
+pub fn severity(level: &tracing::Level) -> u8 {
+    match *level {
+        tracing::Level::ERROR => 3,
+        tracing::Level::WARN => 4,
+        tracing::Level::INFO => 6,
+        _ => 7,
+    }
+}
+
+/// How the lines are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// RFC 3164 datagrams to `/dev/log`
+    Syslog,
+    /// Native journal fields
+    Journald,
+}
+
+/// Identity of the process in the system log
+#[derive(Debug, Clone)]
+pub struct Origin {
+    pub ident: String,
+    pub facility: u8,
+    pub pid: u32,
+}
+
+/// Syslog datagram of a line, the local daemon adds the host name
+pub fn syslog_datagram(
+    origin: &Origin,
+    severity: u8,
+    time: &chrono::DateTime<chrono::Local>,
+    line: &str,
+) -> Vec<u8> {
+    format!(
+        "<{}>{} {}[{}]: {}",
+        u16::from(origin.facility) * 8 + u16::from(severity),
+        time.format("%b %e %H:%M:%S"),
+        origin.ident,
+        origin.pid,
+        line
+    )
+    .into_bytes()
+}
+
+/// Journal datagram of a line
+pub fn journald_datagram(origin: &Origin, severity: u8, line: &str) -> Vec<u8> {
+    let mut fields = String::new();
+    let _ = writeln!(fields, "PRIORITY={}", severity);
+    let _ = writeln!(fields, "SYSLOG_FACILITY={}", origin.facility);
+    let _ = writeln!(fields, "SYSLOG_IDENTIFIER={}", origin.ident);
+    let _ = writeln!(fields, "SYSLOG_PID={}", origin.pid);
+    let mut datagram = fields.into_bytes();
+    if line.contains('\n') {
+        // values with newlines are sent with their length
+        datagram.extend_from_slice(b"MESSAGE\n");
+        datagram.extend_from_slice(&(line.len() as u64).to_le_bytes());
+        datagram.extend_from_slice(line.as_bytes());
+        datagram.push(b'\n');
+    } else {
+        datagram.extend_from_slice(format!("MESSAGE={}\n", line).as_bytes());
+    }
+    datagram
+}
+
+/// Sends the formatted log lines to the system log
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct SyslogWriter {
+    socket: std::sync::Arc<std::os::unix::net::UnixDatagram>,
+    protocol: Protocol,
+    origin: Origin,
+}
+
+#[cfg(unix)]
+impl SyslogWriter {
+    /// Connects to the socket of the local syslog daemon or journal
+    pub fn connect(protocol: Protocol, ident: &str, facility: &str) -> Result<Self> {
+        use anyhow::Context;
+        let path = match protocol {
+            Protocol::Syslog => SYSLOG_SOCKET,
+            Protocol::Journald => JOURNALD_SOCKET,
+        };
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket
+            .connect(path)
+            .with_context(|| format!("Can't connect to {}", path))?;
+        Ok(Self {
+            socket: std::sync::Arc::new(socket),
+            protocol,
+            origin: Origin {
+                ident: ident.to_owned(),
+                facility: facility_code(facility)?,
+                pid: std::process::id(),
+            },
+        })
+    }
+
+    /// Sends one line, failures are ignored as there is nowhere to log
+    /// them
+    fn send(&self, severity: u8, line: &str) {
+        let datagram = match self.protocol {
+            Protocol::Syslog => {
+                syslog_datagram(&self.origin, severity, &chrono::Local::now(), line)
+            }
+            Protocol::Journald => journald_datagram(&self.origin, severity, line),
+        };
+        let _ = self.socket.send(&datagram);
+    }
+}
+
+/// Collects the formatted event and sends it when dropped
+#[cfg(unix)]
+pub struct SyslogLine<'a> {
+    writer: &'a SyslogWriter,
+    severity: u8,
+    buf: Vec<u8>,
+}
+
+#[cfg(unix)]
+impl std::io::Write for SyslogLine<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SyslogLine<'_> {
+    fn drop(&mut self) {
+        let line = String::from_utf8_lossy(&self.buf);
+        let line = crate::redact::redact_credentials(line.trim_end());
+        if !line.is_empty() {
+            self.writer.send(self.severity, &line);
+        }
+    }
+}
+
+#[cfg(unix)]
+impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for SyslogWriter {
+    type Writer = SyslogLine<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogLine {
+            writer: self,
+            severity: severity(&tracing::Level::INFO),
+            buf: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &tracing::Metadata<'_>) -> Self::Writer {
+        SyslogLine {
+            writer: self,
+            severity: severity(meta.level()),
+            buf: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_datagrams() {
+        assert_eq!(facility_code("mail").unwrap(), 2);
+        assert_eq!(facility_code("LOCAL3").unwrap(), 19);
+        assert!(facility_code("printer").is_err());
+
+        let origin = Origin {
+            ident: "invoice2storage".to_owned(),
+            facility: 2,
+            pid: 42,
+        };
+        let time = chrono::TimeZone::with_ymd_and_hms(&chrono::Local, 2023, 5, 2, 9, 4, 0).unwrap();
+        let datagram = syslog_datagram(&origin, severity(&tracing::Level::WARN), &time, "slow");
+        assert_eq!(datagram, b"<20>May  2 09:04:00 invoice2storage[42]: slow");
+
+        let datagram = journald_datagram(&origin, 3, "a");
+        assert_eq!(
+            datagram,
+            b"PRIORITY=3\nSYSLOG_FACILITY=2\nSYSLOG_IDENTIFIER=invoice2storage\nSYSLOG_PID=42\nMESSAGE=a\n"
+        );
+        let datagram = journald_datagram(&origin, 3, "a\nb");
+        assert!(datagram.ends_with(b"MESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n"));
+    }
+}
@@ -0,0 +1,269 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Several tenants served by one instance.
+//!
+//! Each tenant is selected by the domain of a recipient or the mailbox the
+//! source read the mail from, and overrides any setting of the config file,
+//! like the storage targets, templates and the state database:
+//!
+//! ```toml
+//! [[tenants]]
+//! name = "acme"
+//! domains = ["acme.example"]
+//! local_path = "/srv/invoices/acme"
+//! state_db = "/var/lib/invoice2storage/acme.jsonl"
+//! output_template = "{{vendor}}/{{file_name}}"
+//! ```
+//!
+//! Mails of no tenant are processed with the global settings. Tenants
+//! can't share a state database or audit log with each other or with the
+//! global settings.
+
+use crate::pipeline::Message;
+use crate::{CancellationToken, Config, Processor};
+use anyhow::{bail, Context, Result};
+use clap_serde_derive::ClapSerde;
+use mailparse::{MailAddr, MailHeaderMap};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Headers the recipient domains are read from
+const RECIPIENT_HEADERS: [&str; 4] = ["Delivered-To", "X-Original-To", "To", "Cc"];
+
+/// Declaration of a tenant in the config file
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct TenantConfig {
+    /// Name used in the logs and the results
+    pub name: String,
+    /// Recipient domains of the tenant
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// Source mailboxes of the tenant
+    #[serde(default)]
+    pub mailboxes: Vec<String>,
+    /// Settings replacing the global ones, with the keys of the config file
+    #[serde(flatten)]
+    pub settings: toml::value::Table,
+}
+
+impl TenantConfig {
+    /// Global configuration with the settings of the tenant
+    pub fn config(&self, global: &Config) -> Result<Config> {
+        let settings: <Config as ClapSerde>::Opt = toml::Value::Table(self.settings.clone())
+            .try_into()
+            .with_context(|| format!("Invalid settings of tenant {}", self.name))?;
+        let mut config = global.clone().merge(settings);
+        config.tenants.clear();
+        Ok(config)
+    }
+
+    /// Removes the credentials of the urls and the OAuth secret
+    pub fn redact(&mut self) {
+        for (key, value) in self.settings.iter_mut() {
+            match key.as_str() {
+                "imap_url" | "http_path" => {
+                    if let toml::Value::String(url) = value {
+                        *url = crate::redact::redact_credentials(url);
+                    }
+                }
+                // the pipeline may contain urls in several places
+                "oauth_client_secret"
+                | "oauth_refresh_token"
+                | "ldap_bind_password"
+                | "paperless_token"
+                | "http_bearer_token"
+                | "http_header"
+                | "pipeline"
+                | "archive_passwords" => *value = "***".into(),
+                _ => (),
+            }
+        }
+    }
+
+    fn matches(&self, message: &Message, domains: &[String]) -> bool {
+        if let Some(mailbox) = &message.mailbox {
+            if self.mailboxes.iter().any(|x| x == mailbox) {
+                return true;
+            }
+        }
+        self.domains
+            .iter()
+            .any(|x| domains.iter().any(|domain| x.eq_ignore_ascii_case(domain)))
+    }
+}
+
+/// Checks the tenant declarations and their configurations
+pub fn validate(config: &Config) -> Result<()> {
+    let mut names = HashSet::new();
+    let mut files = HashSet::new();
+    for path in [&config.state_db, &config.audit_log].into_iter().flatten() {
+        files.insert(path.clone());
+    }
+    for tenant in config.tenants.iter() {
+        if !names.insert(&tenant.name) {
+            bail!("Tenant {} declared twice", tenant.name);
+        }
+        if tenant.domains.is_empty() && tenant.mailboxes.is_empty() {
+            bail!("Tenant {} needs domains or mailboxes", tenant.name);
+        }
+        let tenant_config = tenant.config(config)?;
+        tenant_config
+            .validate()
+            .with_context(|| format!("Tenant {}", tenant.name))?;
+        for (name, path) in [
+            ("state_db", &tenant_config.state_db),
+            ("audit_log", &tenant_config.audit_log),
+        ] {
+            if let Some(path) = path {
+                if !files.insert(path.clone()) {
+                    bail!(
+                        "Tenant {}: {} {} is shared, set one for each tenant",
+                        tenant.name,
+                        name,
+                        path.display()
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Processor of a tenant
+pub struct Tenant {
+    pub config: TenantConfig,
+    pub processor: Processor,
+}
+
+/// Selects the processor of the tenant of a message
+#[derive(Default)]
+pub struct Tenants {
+    tenants: Vec<Tenant>,
+}
+
+impl Tenants {
+    /// Creates the processors of all declared tenants
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let mut tenants = Vec::new();
+        for tenant in config.tenants.iter() {
+            let processor = Processor::from_config(tenant.config(config)?)
+                .with_context(|| format!("Tenant {}", tenant.name))?;
+            tenants.push(Tenant {
+                config: tenant.clone(),
+                processor,
+            });
+        }
+        Ok(Self { tenants })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tenants.is_empty()
+    }
+
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.tenants = self
+            .tenants
+            .into_iter()
+            .map(|tenant| Tenant {
+                processor: tenant.processor.with_cancellation(cancel.clone()),
+                config: tenant.config,
+            })
+            .collect();
+        self
+    }
+
+    /// See [`Processor::with_redelivery`]
+    pub fn with_redelivery(mut self, redelivery: bool) -> Self {
+        self.tenants = self
+            .tenants
+            .into_iter()
+            .map(|tenant| Tenant {
+                processor: tenant.processor.with_redelivery(redelivery),
+                config: tenant.config,
+            })
+            .collect();
+        self
+    }
+
+    /// Returns the first tenant of the source mailbox or a recipient domain
+    pub fn select(&self, message: &Message) -> Option<&Tenant> {
+        if self.tenants.is_empty() {
+            return None;
+        }
+        let domains = recipient_domains(&message.content);
+        self.tenants
+            .iter()
+            .find(|tenant| tenant.config.matches(message, &domains))
+    }
+}
+
+/// Lowercase domains of all recipient addresses
+fn recipient_domains(content: &[u8]) -> Vec<String> {
+    let headers = match mailparse::parse_headers(content) {
+        Ok((headers, _)) => headers,
+        Err(_) => return Vec::new(),
+    };
+    let mut rv = Vec::new();
+    for name in RECIPIENT_HEADERS {
+        for value in headers.get_all_values(name) {
+            let addresses = match mailparse::addrparse(&value) {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            for address in addresses.iter() {
+                let infos = match address {
+                    MailAddr::Single(info) => vec![info],
+                    MailAddr::Group(group) => group.addrs.iter().collect(),
+                };
+                for info in infos {
+                    if let Some((_, domain)) = info.addr.rsplit_once('@') {
+                        rv.push(domain.to_lowercase());
+                    }
+                }
+            }
+        }
+    }
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let config: TenantConfig = toml::from_str(
+            r#"
+            name = "acme"
+            domains = ["ACME.example"]
+            mailboxes = ["acme-invoices"]
+            local_path = "/srv/acme"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.settings.len(), 1);
+        let global = Config::builder().local_path("/srv/global").build().unwrap();
+        let tenant_config = config.config(&global).unwrap();
+        assert_eq!(tenant_config.local_path, Some("/srv/acme".into()));
+        assert_eq!(tenant_config.unknown_user, global.unknown_user);
+
+        let tenants = Tenants {
+            tenants: vec![Tenant {
+                config,
+                processor: Processor::new(tenant_config),
+            }],
+        };
+        let message = |content: &'static [u8], mailbox: Option<&str>| Message {
+            id: "test".to_owned(),
+            content: content.into(),
+            mailbox: mailbox.map(|x| x.to_owned()),
+        };
+        let acme = message(b"To: Invoices <invoice+bob@acme.example>\r\n\r\n", None);
+        assert!(tenants.select(&acme).is_some());
+        let other = message(b"To: invoice@other.example\r\n\r\n", None);
+        assert!(tenants.select(&other).is_none());
+        let mailbox = message(b"To: invoice@other.example\r\n\r\n", Some("acme-invoices"));
+        assert!(tenants.select(&mailbox).is_some());
+    }
+}
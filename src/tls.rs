@@ -0,0 +1,437 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! TLS settings of the IMAP and HTTPS connections.
+//!
+//! rustls doesn't implement TLS 1.0 and 1.1, the minimum version is
+//! TLS 1.2 and can be raised to TLS 1.3.
+
+use crate::Config;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Lowest TLS version accepted for connections
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize, Serialize, clap::ValueEnum,
+)]
+pub enum TlsVersion {
+    #[default]
+    #[value(name = "1.2")]
+    #[serde(rename = "1.2")]
+    V1_2,
+    #[value(name = "1.3")]
+    #[serde(rename = "1.3")]
+    V1_3,
+}
+
+/// TLS policy and verification settings of a connection
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct TlsOptions {
+    pub min_version: TlsVersion,
+    /// Names of the allowed cipher suites like `TLS13_AES_256_GCM_SHA384`,
+    /// empty for the rustls defaults
+    pub cipher_suites: Vec<String>,
+    /// Don't verify the server certificate
+    pub insecure: bool,
+    /// PEM files or directories of CA certificates trusted in addition to
+    /// the native roots
+    pub ca_paths: Vec<PathBuf>,
+    /// SHA-256 fingerprints of pinned server certificates in hex, colons
+    /// are ignored. A pinned certificate is trusted without checking the
+    /// CA and host name.
+    pub fingerprints: Vec<String>,
+    /// PEM certificate chain and private key for client authentication
+    pub client_identity: Option<(PathBuf, PathBuf)>,
+}
+
+impl TlsOptions {
+    /// Policy, CA file and client certificate of the config, the other
+    /// verification settings are set per target
+    pub fn from_config(config: &Config) -> Self {
+        let client_identity = match (&config.tls_client_cert, &config.tls_client_key) {
+            (Some(cert), Some(key)) => Some((cert.clone(), key.clone())),
+            _ => None,
+        };
+        Self {
+            min_version: config.tls_min_version,
+            cipher_suites: config.tls_ciphers.clone(),
+            insecure: false,
+            ca_paths: config.ca_file.iter().cloned().collect(),
+            fingerprints: Vec::new(),
+            client_identity,
+        }
+    }
+
+    pub fn with_insecure(mut self, insecure: bool) -> Self {
+        self.insecure = insecure;
+        self
+    }
+
+    pub fn with_ca(mut self, path: PathBuf) -> Self {
+        self.ca_paths.push(path);
+        self
+    }
+
+    pub fn with_fingerprints(mut self, fingerprints: &[String]) -> Self {
+        self.fingerprints.extend(fingerprints.iter().cloned());
+        self
+    }
+
+    /// Authenticates with the certificate chain and key, both PEM files.
+    /// Replaces the client certificate of the config.
+    pub fn with_client_identity(mut self, cert: PathBuf, key: PathBuf) -> Self {
+        self.client_identity = Some((cert, key));
+        self
+    }
+
+    fn pinned(&self) -> Result<Vec<[u8; 32]>> {
+        self.fingerprints
+            .iter()
+            .map(|x| parse_fingerprint(x))
+            .collect()
+    }
+
+    fn cipher_suites(&self) -> Result<Vec<rustls::SupportedCipherSuite>> {
+        if self.cipher_suites.is_empty() {
+            return Ok(rustls::DEFAULT_CIPHER_SUITES.to_vec());
+        }
+        self.cipher_suites
+            .iter()
+            .map(|name| {
+                rustls::ALL_CIPHER_SUITES
+                    .iter()
+                    .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name.trim()))
+                    .copied()
+                    .ok_or_else(|| anyhow!("Unknown TLS cipher suite: {}", name))
+            })
+            .collect()
+    }
+
+    fn builder(
+        &self,
+    ) -> Result<rustls::ConfigBuilder<rustls::ClientConfig, rustls::WantsVerifier>> {
+        let versions: &[&'static rustls::SupportedProtocolVersion] = match self.min_version {
+            TlsVersion::V1_2 => &[&rustls::version::TLS13, &rustls::version::TLS12],
+            TlsVersion::V1_3 => &[&rustls::version::TLS13],
+        };
+        rustls::ClientConfig::builder()
+            .with_cipher_suites(&self.cipher_suites()?)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)
+            .context("Invalid TLS policy")
+    }
+
+    /// Checks the cipher suites and version without loading certificates
+    pub fn validate(&self) -> Result<()> {
+        self.pinned()?;
+        self.builder().map(|_| ())
+    }
+
+    /// Creates the rustls configuration trusting the native root certificates
+    pub fn client_config(&self) -> Result<rustls::ClientConfig> {
+        let mut roots = native_roots();
+        for path in self.ca_paths.iter() {
+            add_ca_certificates(&mut roots, path)?;
+        }
+        let builder = self.builder()?.with_root_certificates(roots);
+        let mut config = match &self.client_identity {
+            Some((cert, key)) => builder
+                .with_single_cert(load_certificates(cert)?, load_private_key(key)?)
+                .context("Invalid client certificate")?,
+            None => builder.with_no_client_auth(),
+        };
+        let pinned = self.pinned()?;
+        if !pinned.is_empty() {
+            if self.insecure {
+                log::warn!("Certificate pinned, insecure mode is ignored");
+            }
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(PinnedCertificateVerifier { pinned }));
+        } else if self.insecure {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoCertificateVerification {}));
+        }
+        Ok(config)
+    }
+}
+
+/// Loads the root certificates of the operating system
+fn native_roots() -> rustls::RootCertStore {
+    let mut root_store = rustls::RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            for cert in certs {
+                if let Err(err) = root_store.add(&rustls::Certificate(cert.0)) {
+                    log::warn!(
+                        "Got error while importing some native certificates: {:?}",
+                        err
+                    );
+                }
+            }
+        }
+        Err(err) => log::error!("Could not load platform certificates: {}", err),
+    }
+    root_store
+}
+
+/// Checks that the PEM file or directory contains CA certificates
+pub fn check_ca_certificates(path: &Path) -> Result<()> {
+    add_ca_certificates(&mut rustls::RootCertStore::empty(), path)
+}
+
+/// Adds the certificates of a PEM file or all `*.pem` and `*.crt` files of
+/// a directory
+fn add_ca_certificates(roots: &mut rustls::RootCertStore, path: &Path) -> Result<()> {
+    let files = if path.is_dir() {
+        let mut files: Vec<PathBuf> = std::fs::read_dir(path)
+            .with_context(|| format!("Can't read CA directory {}", path.display()))?
+            .filter_map(|entry| entry.ok().map(|x| x.path()))
+            .filter(|x| {
+                matches!(
+                    x.extension().and_then(|ext| ext.to_str()),
+                    Some("pem") | Some("crt")
+                )
+            })
+            .collect();
+        files.sort();
+        files
+    } else {
+        vec![path.to_owned()]
+    };
+    let mut added = 0;
+    for file in files {
+        let mut reader = std::io::BufReader::new(
+            std::fs::File::open(&file)
+                .with_context(|| format!("Can't open CA file {}", file.display()))?,
+        );
+        let certs = rustls_pemfile::certs(&mut reader)
+            .with_context(|| format!("Can't parse CA file {}", file.display()))?;
+        let (valid, _) = roots.add_parsable_certificates(&certs);
+        added += valid;
+    }
+    if added == 0 {
+        bail!("No CA certificates found in {}", path.display());
+    }
+    log::debug!("Added {} CA certificates from {}", added, path.display());
+    Ok(())
+}
+
+/// Reads the certificate chain of a PEM file
+fn load_certificates(path: &Path) -> Result<Vec<rustls::Certificate>> {
+    let mut reader = std::io::BufReader::new(
+        std::fs::File::open(path)
+            .with_context(|| format!("Can't open certificate {}", path.display()))?,
+    );
+    let certs = rustls_pemfile::certs(&mut reader)
+        .with_context(|| format!("Can't parse certificate {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("No certificate found in {}", path.display());
+    }
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+/// Reads the first PKCS#8, RSA or EC private key of a PEM file
+fn load_private_key(path: &Path) -> Result<rustls::PrivateKey> {
+    let mut reader = std::io::BufReader::new(
+        std::fs::File::open(path)
+            .with_context(|| format!("Can't open private key {}", path.display()))?,
+    );
+    let items = rustls_pemfile::read_all(&mut reader)
+        .with_context(|| format!("Can't parse private key {}", path.display()))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .with_context(|| format!("No private key found in {}", path.display()))
+}
+
+/// Parses a hex SHA-256 fingerprint like `AB:CD:...`
+fn parse_fingerprint(text: &str) -> Result<[u8; 32]> {
+    let hex: String = text
+        .chars()
+        .filter(|x| *x != ':' && !x.is_whitespace())
+        .collect();
+    let mut fingerprint = [0u8; 32];
+    if hex.len() != 64 || !hex.is_ascii() {
+        bail!("Invalid SHA-256 fingerprint: {}", text);
+    }
+    for (index, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16)
+            .with_context(|| format!("Invalid SHA-256 fingerprint: {}", text))?;
+    }
+    Ok(fingerprint)
+}
+
+/// Hex SHA-256 fingerprint of a DER certificate, as printed by
+/// `openssl x509 -noout -fingerprint -sha256`
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|x| format!("{:02X}", x))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Trusts only certificates with a pinned fingerprint. The handshake
+/// signatures are still verified by the default implementations.
+struct PinnedCertificateVerifier {
+    pinned: Vec<[u8; 32]>,
+}
+
+impl rustls::client::ServerCertVerifier for PinnedCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> std::result::Result<rustls::client::ServerCertVerified, rustls::Error> {
+        let digest: [u8; 32] = Sha256::digest(&end_entity.0).into();
+        if self.pinned.contains(&digest) {
+            Ok(rustls::client::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "Certificate fingerprint {} is not pinned",
+                fingerprint(&end_entity.0)
+            )))
+        }
+    }
+}
+
+/// Verifier does not verify anything. Used with the insecure options
+struct NoCertificateVerification {}
+impl rustls::client::ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> std::result::Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &rustls::Certificate,
+        _dss: &rustls::DigitallySignedStruct,
+    ) -> std::result::Result<rustls::client::HandshakeSignatureValid, rustls::Error> {
+        Ok(rustls::client::HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &rustls::Certificate,
+        _dss: &rustls::DigitallySignedStruct,
+    ) -> std::result::Result<rustls::client::HandshakeSignatureValid, rustls::Error> {
+        Ok(rustls::client::HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        rustls::client::WebPkiVerifier::verification_schemes()
+    }
+
+    fn request_scts(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tls_policy() {
+        assert!(TlsOptions::default().validate().is_ok());
+        let options = TlsOptions {
+            min_version: TlsVersion::V1_3,
+            cipher_suites: vec!["tls13_aes_256_gcm_sha384".to_owned()],
+            ..Default::default()
+        };
+        assert!(options.validate().is_ok());
+        // a TLS 1.2 suite can't be used with TLS 1.3 only
+        let options = TlsOptions {
+            min_version: TlsVersion::V1_3,
+            cipher_suites: vec!["TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384".to_owned()],
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+        let options = TlsOptions {
+            cipher_suites: vec!["TLS_RSA_WITH_RC4_128_MD5".to_owned()],
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+    }
+
+    #[test]
+    fn test_ca_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        assert!(add_ca_certificates(&mut roots, dir.path()).is_err());
+        std::fs::write(dir.path().join("internal.pem"), "not a certificate").unwrap();
+        assert!(add_ca_certificates(&mut roots, dir.path()).is_err());
+        assert!(add_ca_certificates(&mut roots, &dir.path().join("missing.pem")).is_err());
+        assert!(check_ca_certificates(dir.path()).is_err());
+
+        let mut config = crate::Config::default();
+        config.ca_file = Some(dir.path().join("internal.pem"));
+        let options = TlsOptions::from_config(&config).with_ca(dir.path().join("imap.pem"));
+        assert_eq!(options.ca_paths.len(), 2);
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let text = fingerprint(b"certificate");
+        assert_eq!(text.len(), 95);
+        let digest: [u8; 32] = Sha256::digest(b"certificate").into();
+        assert_eq!(parse_fingerprint(&text).unwrap(), digest);
+        assert_eq!(
+            parse_fingerprint(&text.replace(':', "").to_lowercase()).unwrap(),
+            parse_fingerprint(&text).unwrap()
+        );
+        assert!(parse_fingerprint("AB:CD").is_err());
+        let options = TlsOptions::default().with_fingerprints(&["zz".repeat(32)]);
+        assert!(options.validate().is_err());
+    }
+
+    #[test]
+    fn test_client_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = dir.path().join("client.pem");
+        std::fs::write(
+            &cert,
+            "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+        assert_eq!(load_certificates(&cert).unwrap().len(), 1);
+        // a certificate is no key
+        assert!(load_private_key(&cert).is_err());
+        assert!(load_certificates(&dir.path().join("missing.pem")).is_err());
+
+        let mut config = crate::Config::default();
+        config.tls_client_cert = Some(cert.clone());
+        assert_eq!(TlsOptions::from_config(&config).client_identity, None);
+        config.tls_client_key = Some(dir.path().join("client.key"));
+        let options = TlsOptions::from_config(&config)
+            .with_client_identity(cert.clone(), dir.path().join("http.key"));
+        assert_eq!(
+            options.client_identity,
+            Some((cert, dir.path().join("http.key")))
+        );
+    }
+}
@@ -0,0 +1,221 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Sources of the user a mail is stored for.
+//!
+//! `--user-from` lists the sources in the order they are tried, the first
+//! one that finds a user wins:
+//!
+//! * `to-plus`: the tag of a plus address in `To`, `invoice+alice@…`
+//! * `same-domain`: the local part of `From` if `From` and `To` share the
+//!   domain
+//! * `from-local`: the local part of `From`
+//! * `header:NAME`: the value of the header, of an address its plus tag or
+//!   local part
+//!
+//! The found user can be mapped to a directory account afterwards, see
+//! [`crate::ldap`].
+
+use anyhow::{anyhow, bail};
+use mailparse::{MailAddr, MailHeaderMap, ParsedMail};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::str::FromStr;
+
+pub const DEFAULT_USER_FROM: &str = "to-plus,same-domain";
+
+/// A way to find the user of a mail
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum UserSource {
+    ToPlus,
+    SameDomain,
+    FromLocal,
+    /// Name of the header
+    Header(String),
+}
+
+impl UserSource {
+    /// Returns the user this source finds in the message
+    pub fn extract(&self, message: &ParsedMail) -> Option<String> {
+        match self {
+            UserSource::ToPlus => crate::user_from_plus_address(message),
+            UserSource::SameDomain => crate::user_from_same_domain(message),
+            UserSource::FromLocal => {
+                let from_ = message.headers.get_first_value("from")?;
+                local_part(&from_)
+            }
+            UserSource::Header(name) => {
+                let value = message.headers.get_first_value(name)?;
+                let value = value.trim();
+                if value.contains('@') {
+                    plus_tag(value).or_else(|| local_part(value))
+                } else if value.is_empty() {
+                    None
+                } else {
+                    Some(value.to_owned())
+                }
+            }
+        }
+    }
+}
+
+impl Display for UserSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UserSource::ToPlus => write!(f, "to-plus"),
+            UserSource::SameDomain => write!(f, "same-domain"),
+            UserSource::FromLocal => write!(f, "from-local"),
+            UserSource::Header(name) => write!(f, "header:{}", name),
+        }
+    }
+}
+
+impl FromStr for UserSource {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some((kind, name)) = s.split_once(':') {
+            if !kind.eq_ignore_ascii_case("header") {
+                bail!("Unknown user source {}", s);
+            }
+            if name.trim().is_empty() {
+                bail!("User source {} has no header name", s);
+            }
+            return Ok(UserSource::Header(name.trim().to_owned()));
+        }
+        match s.to_ascii_lowercase().as_str() {
+            "to-plus" => Ok(UserSource::ToPlus),
+            "same-domain" => Ok(UserSource::SameDomain),
+            "from-local" => Ok(UserSource::FromLocal),
+            "ldap" => Err(anyhow!(
+                "ldap is not a user source, set ldap_url to map the found user"
+            )),
+            _ => Err(anyhow!(
+                "Unknown user source {}, expected to-plus, same-domain, from-local or header:NAME",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for UserSource {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<UserSource> for String {
+    fn from(value: UserSource) -> Self {
+        value.to_string()
+    }
+}
+
+/// User source argument list
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[repr(transparent)]
+pub struct UserSources(pub Vec<UserSource>);
+
+impl UserSources {
+    /// Returns the user of the first source that finds one
+    pub fn extract(&self, message: &ParsedMail) -> Option<String> {
+        self.0.iter().find_map(|x| x.extract(message))
+    }
+}
+
+impl Display for UserSources {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sources: Vec<String> = self.0.iter().map(|x| x.to_string()).collect();
+        write!(f, "{}", sources.join(","))
+    }
+}
+
+impl Default for UserSources {
+    fn default() -> Self {
+        DEFAULT_USER_FROM.parse().unwrap()
+    }
+}
+
+impl FromStr for UserSources {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let sources = s
+            .split(',')
+            .filter(|x| !x.trim().is_empty())
+            .map(|x| x.parse())
+            .collect::<Result<Vec<UserSource>, _>>()?;
+        Ok(UserSources(sources))
+    }
+}
+
+impl Into<clap::builder::OsStr> for UserSources {
+    fn into(self) -> clap::builder::OsStr {
+        self.to_string().into()
+    }
+}
+
+/// First address of a header value
+pub(crate) fn first_address(value: &str) -> Option<String> {
+    match mailparse::addrparse(value).ok()?.first()? {
+        MailAddr::Single(info) => Some(info.addr.clone()),
+        MailAddr::Group(group) => group.addrs.first().map(|x| x.addr.clone()),
+    }
+}
+
+/// Local part of the first address without a plus tag
+fn local_part(value: &str) -> Option<String> {
+    let address = first_address(value)?;
+    let (local, _) = address.split_once('@')?;
+    let user = local.split('+').next().unwrap_or_default();
+    (!user.is_empty()).then(|| user.to_owned())
+}
+
+/// Plus tag of the first address
+fn plus_tag(value: &str) -> Option<String> {
+    let address = first_address(value)?;
+    let (local, _) = address.split_once('@')?;
+    let (_, tag) = local.split_once('+')?;
+    (!tag.is_empty()).then(|| tag.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_sources() {
+        let sources: UserSources = "to-plus, header:X-Original-To,from-local".parse().unwrap();
+        assert_eq!(
+            sources.to_string(),
+            "to-plus,header:X-Original-To,from-local"
+        );
+        assert!("ldap".parse::<UserSources>().is_err());
+        assert!("header:".parse::<UserSources>().is_err());
+
+        let message = mailparse::parse_mail(
+            b"From: Bob <bob+x@vendor.com>\nTo: office@example.com\n\
+              X-Original-To: invoice+alice@example.com\nX-User: carol\n\n",
+        )
+        .unwrap();
+        assert_eq!(sources.extract(&message), Some("alice".to_owned()));
+        let from_local: UserSources = "same-domain,from-local".parse().unwrap();
+        assert_eq!(from_local.extract(&message), Some("bob".to_owned()));
+        let header: UserSources = "header:x-user".parse().unwrap();
+        assert_eq!(header.extract(&message), Some("carol".to_owned()));
+        assert_eq!(UserSources::default().extract(&message), None);
+
+        let undisclosed = mailparse::parse_mail(
+            b"From: bob@vendor.com\nTo: undisclosed-recipients:;\n\
+              X-Original-To: invoice+alice@example.com\n\n",
+        )
+        .unwrap();
+        assert_eq!(UserSource::ToPlus.extract(&undisclosed), None);
+        assert_eq!(sources.extract(&undisclosed), Some("alice".to_owned()));
+        let group = mailparse::parse_mail(
+            b"To: Accounting: invoice+dave@example.com, office@example.com;\n\n",
+        )
+        .unwrap();
+        assert_eq!(UserSource::ToPlus.extract(&group), Some("dave".to_owned()));
+    }
+}
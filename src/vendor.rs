@@ -0,0 +1,235 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Maps the sender of an invoice to a canonical vendor identifier, which is
+//! available as `{{vendor}}` in the templates.
+//!
+//! Vendors are matched in this order:
+//! 1. the domain of the from address, including subdomains
+//! 2. a part of the display name of the from address
+//! 3. a text contained in the attachment, like the VAT id of the vendor
+//!
+//! The entries of the mapping file are checked before the built-in table.
+//! Unknown senders get the name of their domain, `billing.acme-corp.de`
+//! becomes `acme-corp`.
+
+use anyhow::{Context, Result};
+use mailparse::MailAddr;
+use serde::Deserialize;
+use std::path::Path;
+
+/// Vendor if the sender has no address
+pub const UNKNOWN_VENDOR: &str = "unknown";
+
+/// Entry of the vendor table
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Vendor {
+    /// Canonical identifier used in the templates
+    pub id: String,
+    /// Sender domains, subdomains match as well
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// Case insensitive parts of the sender display name
+    #[serde(default)]
+    pub names: Vec<String>,
+    /// Texts in the attachment identifying the vendor, like the VAT id
+    #[serde(default)]
+    pub hints: Vec<String>,
+}
+
+impl Vendor {
+    fn new(id: &str, domains: &[&str]) -> Self {
+        Self {
+            id: id.to_owned(),
+            domains: domains.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Format of the mapping file
+#[derive(Debug, Deserialize)]
+struct VendorFile {
+    #[serde(default)]
+    vendor: Vec<Vendor>,
+}
+
+/// Vendors known without a mapping file
+fn builtin() -> Vec<Vendor> {
+    vec![
+        Vendor::new("adobe", &["adobe.com"]),
+        Vendor::new(
+            "amazon",
+            &["amazon.de", "amazon.com", "amazon.co.uk", "amazonaws.com"],
+        ),
+        Vendor::new("apple", &["apple.com", "itunes.com"]),
+        Vendor::new("atlassian", &["atlassian.com"]),
+        Vendor::new("deutsche-bahn", &["bahn.de", "deutschebahn.com"]),
+        Vendor::new("github", &["github.com"]),
+        Vendor::new("google", &["google.com", "payments-noreply.google.com"]),
+        Vendor::new("hetzner", &["hetzner.com", "hetzner.de"]),
+        Vendor::new("ionos", &["ionos.de", "ionos.com", "1und1.de"]),
+        Vendor::new("microsoft", &["microsoft.com", "microsoftonline.com"]),
+        Vendor::new("o2", &["o2online.de", "telefonica.de"]),
+        Vendor::new("paypal", &["paypal.de", "paypal.com"]),
+        Vendor::new("telekom", &["telekom.de", "t-online.de", "telekom.com"]),
+        Vendor::new("vodafone", &["vodafone.de", "vodafone.com"]),
+    ]
+}
+
+/// Resolves vendors with the mapping file entries and the built-in table
+#[derive(Debug, Clone)]
+pub struct VendorTable {
+    vendors: Vec<Vendor>,
+}
+
+impl Default for VendorTable {
+    fn default() -> Self {
+        Self { vendors: builtin() }
+    }
+}
+
+impl VendorTable {
+    /// Adds the vendors of a toml mapping file in front of the built-in ones
+    pub fn with_file(mut self, path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Can't read vendor map {}", path.display()))?;
+        let file: VendorFile = toml::from_str(&content)
+            .with_context(|| format!("Invalid vendor map {}", path.display()))?;
+        self.vendors.splice(0..0, file.vendor);
+        Ok(self)
+    }
+
+    /// Returns the vendor of the `From` header. The content is only
+    /// searched for hints if the sender is not known.
+    pub fn resolve(&self, from: &str, content: Option<&[u8]>) -> String {
+        let (name, domain) = parse_from(from);
+        if let Some(domain) = &domain {
+            let found = self.vendors.iter().find(|vendor| {
+                vendor
+                    .domains
+                    .iter()
+                    .any(|x| domain_matches(domain, &x.to_lowercase()))
+            });
+            if let Some(vendor) = found {
+                return vendor.id.clone();
+            }
+        }
+        if let Some(name) = &name {
+            let found = self.vendors.iter().find(|vendor| {
+                vendor
+                    .names
+                    .iter()
+                    .any(|x| name.contains(&x.to_lowercase()))
+            });
+            if let Some(vendor) = found {
+                return vendor.id.clone();
+            }
+        }
+        if let Some(content) = content {
+            let found = self.vendors.iter().find(|vendor| {
+                vendor
+                    .hints
+                    .iter()
+                    .any(|x| !x.is_empty() && contains(content, x.as_bytes()))
+            });
+            if let Some(vendor) = found {
+                return vendor.id.clone();
+            }
+        }
+        domain
+            .as_deref()
+            .map(domain_vendor)
+            .unwrap_or_else(|| UNKNOWN_VENDOR.to_owned())
+    }
+}
+
+/// Lowercase display name and domain of the first address
+fn parse_from(from: &str) -> (Option<String>, Option<String>) {
+    let addresses = match mailparse::addrparse(from) {
+        Ok(x) => x,
+        Err(_) => return (None, None),
+    };
+    for address in addresses.iter() {
+        if let MailAddr::Single(info) = address {
+            let domain = info
+                .addr
+                .rsplit_once('@')
+                .map(|(_, domain)| domain.trim_end_matches('>').to_lowercase());
+            let name = info.display_name.as_ref().map(|x| x.to_lowercase());
+            return (name, domain);
+        }
+    }
+    (None, None)
+}
+
+fn domain_matches(domain: &str, pattern: &str) -> bool {
+    domain == pattern
+        || domain
+            .strip_suffix(pattern)
+            .map(|x| x.ends_with('.'))
+            .unwrap_or(false)
+}
+
+/// Name of the domain without subdomains and top level domain
+fn domain_vendor(domain: &str) -> String {
+    let labels: Vec<&str> = domain.split('.').filter(|x| !x.is_empty()).collect();
+    let name = match labels.len() {
+        0 => return UNKNOWN_VENDOR.to_owned(),
+        1 => labels[0],
+        // second level registrations like example.co.uk
+        n if n >= 3 && matches!(labels[n - 2], "co" | "com" | "org" | "ac" | "gv") => labels[n - 3],
+        n => labels[n - 2],
+    };
+    name.to_owned()
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|x| x == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vendors.toml");
+        std::fs::write(
+            &path,
+            r#"
+            [[vendor]]
+            id = "acme"
+            domains = ["acme-billing.example"]
+            names = ["ACME Corp"]
+            hints = ["DE123456789"]
+            "#,
+        )
+        .unwrap();
+        let table = VendorTable::default().with_file(&path).unwrap();
+
+        assert_eq!(
+            table.resolve("Amazon.de <rechnung@amazon.de>", None),
+            "amazon"
+        );
+        assert_eq!(table.resolve("noreply@mail.telekom.de", None), "telekom");
+        assert_eq!(table.resolve("invoice@acme-billing.example", None), "acme");
+        assert_eq!(
+            table.resolve("\"ACME Corp Billing\" <x@mailer.example>", None),
+            "acme"
+        );
+        assert_eq!(
+            table.resolve("x@mailer.example", Some(b"USt-IdNr. DE123456789")),
+            "acme"
+        );
+        assert_eq!(
+            table.resolve("billing@shop.acme-corp.de", None),
+            "acme-corp"
+        );
+        assert_eq!(table.resolve("a@example.co.uk", None), "example");
+        assert_eq!(table.resolve("", None), UNKNOWN_VENDOR);
+        // a domain ending with a known one is not the vendor
+        assert_eq!(table.resolve("a@notamazon.de", None), "notamazon");
+    }
+}
@@ -0,0 +1,207 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! Watches a drop folder, like the scan inbox of an office scanner, and
+//! stores new documents with [`Processor::ingest`]
+
+use crate::{ErrorCategory, ProcessResult, Processor};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Default time between two scans of the drop folder
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Polls a directory for new documents. The directory is polled instead of
+/// using inotify, which doesn't work on the network shares scanners write to.
+#[derive(Debug)]
+pub struct DropFolder {
+    inbox: PathBuf,
+    done: PathBuf,
+    failed: PathBuf,
+    user: Option<String>,
+    /// Sizes seen on the last scan, files are processed once the size is stable
+    pending: HashMap<PathBuf, u64>,
+}
+
+impl DropFolder {
+    /// Processed documents are moved to `done` and `failed` in the inbox
+    pub fn new(inbox: PathBuf) -> Self {
+        Self {
+            done: inbox.join("done"),
+            failed: inbox.join("failed"),
+            inbox,
+            user: None,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Folder successfully stored documents are moved to
+    pub fn with_done(mut self, done: PathBuf) -> Self {
+        self.done = done;
+        self
+    }
+
+    /// Folder documents are moved to if they can't be stored
+    pub fn with_failed(mut self, failed: PathBuf) -> Self {
+        self.failed = failed;
+        self
+    }
+
+    /// User all documents of the folder belong to
+    pub fn with_user(mut self, user: Option<String>) -> Self {
+        self.user = user;
+        self
+    }
+
+    /// Inbox and the folders documents are moved to
+    pub fn folders(&self) -> Vec<PathBuf> {
+        vec![self.inbox.clone(), self.done.clone(), self.failed.clone()]
+    }
+
+    /// Scans the inbox once and stores all documents that did not change
+    /// since the last scan. Hidden and temporary files are ignored.
+    pub async fn poll(&mut self, processor: &Processor) -> Result<Vec<ProcessResult>> {
+        let mut sizes = HashMap::new();
+        let entries = std::fs::read_dir(&self.inbox)
+            .with_context(|| format!("Can't read drop folder {}", self.inbox.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let path = entry.path();
+            if !metadata.is_file() || is_ignored(&path) {
+                continue;
+            }
+            sizes.insert(path, metadata.len());
+        }
+
+        let mut results = Vec::new();
+        let mut stable: Vec<&PathBuf> = sizes
+            .iter()
+            .filter(|(path, size)| self.pending.get(*path) == Some(*size))
+            .map(|(path, _)| path)
+            .collect();
+        stable.sort();
+        for path in stable {
+            if processor.cancellation_token().is_cancelled() {
+                break;
+            }
+            results.push(self.file(processor, path).await);
+        }
+        self.pending = sizes;
+        Ok(results)
+    }
+
+    /// Polls the inbox until the processor is cancelled
+    pub async fn run(&mut self, processor: &Processor, interval: Duration) -> Result<()> {
+        let cancel = processor.cancellation_token();
+        loop {
+            for result in self.poll(processor).await? {
+                if result.is_success() {
+                    log::info!("{}", result);
+                } else {
+                    log::error!("{}", result);
+                }
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => (),
+                _ = cancel.cancelled() => return Ok(()),
+            }
+        }
+    }
+
+    /// Stores the document and moves it out of the inbox
+    async fn file(&self, processor: &Processor, path: &Path) -> ProcessResult {
+        let file_name = path
+            .file_name()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut result = match tokio::fs::read(path).await {
+            Ok(content) => {
+                processor
+                    .ingest(&file_name, content.into(), self.user.clone(), None)
+                    .await
+            }
+            Err(err) => {
+                let mut rv = ProcessResult::default();
+                rv.add_error(ErrorCategory::Input, format!("{:#}", err));
+                rv
+            }
+        };
+        result.message = Some(path.display().to_string());
+        processor.audit(&mut result);
+        let target = if result.is_success() {
+            &self.done
+        } else {
+            &self.failed
+        };
+        if let Err(err) = move_to(path, target) {
+            log::error!(
+                "Can't move {} to {}: {:#}",
+                path.display(),
+                target.display(),
+                err
+            );
+            result.add_error(ErrorCategory::Output, format!("{:#}", err));
+        }
+        result
+    }
+}
+
+/// Files still written by the scanner or hidden
+fn is_ignored(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|x| x.to_string_lossy())
+        .unwrap_or_default();
+    name.starts_with('.') || name.ends_with(".part") || name.ends_with(".tmp")
+}
+
+/// Moves the file into the folder without overwriting existing files
+fn move_to(path: &Path, folder: &Path) -> Result<PathBuf> {
+    std::fs::create_dir_all(folder)?;
+    let file_name = path.file_name().context("File has no name")?;
+    let mut target = folder.join(file_name);
+    let mut counter = 1;
+    while target.exists() {
+        target = folder.join(format!("{}.{}", counter, file_name.to_string_lossy()));
+        counter += 1;
+    }
+    if std::fs::rename(path, &target).is_err() {
+        // rename fails across file systems
+        std::fs::copy(path, &target)?;
+        std::fs::remove_file(path)?;
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_drop_folder() {
+        let inbox = tempfile::tempdir().unwrap();
+        std::fs::write(inbox.path().join("scan.pdf"), b"%PDF-1.4").unwrap();
+        std::fs::write(inbox.path().join("writing.pdf.part"), b"%PDF").unwrap();
+        let config = Config::builder()
+            .local_path("/nonexistent")
+            .build()
+            .unwrap();
+        let store = Arc::new(object_store::memory::InMemory::new());
+        let processor = Processor::new(config).with_object_store(store);
+        let mut folder = DropFolder::new(inbox.path().to_owned()).with_user(Some("office".into()));
+
+        // the first scan only records the size
+        assert!(folder.poll(&processor).await.unwrap().is_empty());
+        let results = folder.poll(&processor).await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].files, vec!["office/scan.pdf".to_owned()]);
+        assert!(inbox.path().join("done/scan.pdf").exists());
+        assert!(!inbox.path().join("scan.pdf").exists());
+        assert!(inbox.path().join("writing.pdf.part").exists());
+    }
+}
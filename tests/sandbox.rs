@@ -0,0 +1,39 @@
+// copyright: B1 Systems GmbH <info@b1-systems.de>, 2023
+// license: GPLv3+, http://www.gnu.org/licenses/gpl-3.0.html
+
+//! The sandbox confines the whole process, so it is tested with the binary.
+
+use std::process::Command;
+
+/// CA bundle of the test system, copied to a path the sandbox doesn't allow
+const SYSTEM_CA: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_sandbox_after_setup() {
+    if !std::path::Path::new(SYSTEM_CA).exists() {
+        return;
+    }
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR")).unwrap();
+    let ca_file = dir.path().join("ca.pem");
+    std::fs::copy(SYSTEM_CA, &ca_file).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_invoice2storage"))
+        .arg("--config-file")
+        .arg(dir.path().join("missing.toml"))
+        .args(["-vv", "--sandbox"])
+        .arg("--ca-file")
+        .arg(&ca_file)
+        .args(["--http-path", "https://127.0.0.1:1/dav/"])
+        .args(["--retry-timeout", "0"])
+        .args(["process", "test-data/test_email1.eml"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("Can't enable sandbox") {
+        // kernel without Landlock
+        return;
+    }
+    // the CA file is read while the backends are set up
+    assert!(!stderr.contains("Can't setup processing"), "{}", stderr);
+    assert!(stderr.contains("Sandbox enabled"), "{}", stderr);
+}